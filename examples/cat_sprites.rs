@@ -1,7 +1,9 @@
-use cgmath::{One, Quaternion};
-use functional_game_engine::game::entity::{Change, Component};
+use functional_game_engine::game::entity::Component;
 use functional_game_engine::game::GameState;
-use functional_game_engine::game::transform::{Transform2D, Transform3D, TRANSFORM_COMP_NAME};
+use functional_game_engine::game::schedule::Times;
+use functional_game_engine::game::time::Time;
+use functional_game_engine::game::query::{Query, Resource, With, Without};
+use functional_game_engine::game::transform::Transform2D;
 use functional_game_engine::asset::AssetsToLoad;
 use functional_game_engine::render::sprite_render::SpriteComponent;
 use functional_game_engine::run;
@@ -17,62 +19,58 @@ fn main() {
 
     let mut game_state = GameState::new();
 
-    {
-        let mut e1 = game_state.new_entity_mut();
-        Transform2D { pos: [-1., -0.2], size: [0.5, 0.5], rot: 0. }.to_entity(e1);
-        e1.mut_data().alloc(Tag { _i: 10 }, "tag");
-        SpriteComponent::new(0).to_entity(&mut e1);
-    }
-    {
-        let mut e2 = game_state.new_entity_mut();
-        Transform2D { pos: [-1., -1.], size: [1.0, 0.5], rot: 1.0 }.to_entity(e2);
-        SpriteComponent::new(0).to_entity(&mut e2);
-    }
-    /*{
-        let mut e3 = game_state.new_entity_mut();
-        Transform3D {
-            pos: [0., 0., 0.],
-            size: [1.0, 1.0, 1.0],
-            rotation: Quaternion::one(),
-        }.to_entity(e3);
-        // ModelComponent::new()
-    }*/
+    game_state.add_world_system(Times::Startup, |world| {
+        {
+            let e1 = world.new_entity_mut();
+            Transform2D { pos: [-1., -0.2], size: [0.5, 0.5], rot: 0. }.to_entity(e1);
+            e1.insert(Tag { _i: 10 });
+            SpriteComponent::new(0).to_entity(e1);
+        }
+        {
+            let e2 = world.new_entity_mut();
+            Transform2D { pos: [-1., -1.], size: [1.0, 0.5], rot: 1.0 }.to_entity(e2);
+            SpriteComponent::new(0).to_entity(e2);
+        }
+        /*{
+            let mut e3 = world.new_entity_mut();
+            Transform3D {
+                pos: [0., 0., 0.],
+                size: [1.0, 1.0, 1.0],
+                rotation: Quaternion::one(),
+            }.to_entity(e3);
+            // ModelComponent::new()
+        }*/
+    });
 
-    game_state.linear_systems.push(|entity| {
-        if let Some(mut p) = entity.data().get::<Transform2D>(TRANSFORM_COMP_NAME) {
-            if entity.data().has("tag") {
-                if p.pos[0] > 1.0 {
-                    p.pos[0] = -1.;
-                } else {
-                    p.pos[0] += 0.01;
-                }
-                Some(Change::new(p, TRANSFORM_COMP_NAME))
-            } else {
-                if p.rot > 2.0 {
-                    p.rot = 0.0;
-                } else {
-                    p.rot += 0.01;
-                }
-                Some(Change::new(p, TRANSFORM_COMP_NAME))
-            }
+    // tagged cats move to the right, wrapping around the screen
+    type Moving = (&'static mut Transform2D, Resource<Time>);
+    let speed = 0.3;
+    game_state.add_query_system(Times::SimulationTick, Query::<Moving, With<Tag>>::new(move |(p, time)| {
+        if p.pos[0] > 1.0 {
+            p.pos[0] = -1.;
         } else {
-            None
+            p.pos[0] += speed * time.delta_secs();
         }
-    });
+        None
+    }));
 
-    // example quadratic system:
-    /* // spams the console a lot
-    game_state.quadratic_systems.push(|entity, other| {
-        if let Some(pos1) = entity.data().get::<Transform2D>("pos") {
-            if let Some(pos2) = other.data().get::<Transform2D>("pos") {
-                if Transform2D::dist(pos1, pos2) <= 1.0 {
-                    println!("Entity:{} and Entity:{} are close!", entity.id(), other.id());
-                }
-            }
+    // the others spin
+    game_state.add_query_system(Times::SimulationTick, Query::<Moving, Without<Tag>>::new(move |(p, time)| {
+        if p.rot > 2.0 {
+            p.rot = 0.0;
+        } else {
+            p.rot += speed * time.delta_secs();
         }
+        None
+    }));
 
+    // example quadratic system:
+    /* // spams the console a lot
+    // only pairs within the radius are visited, so the system never sees the far away ones
+    game_state.add_quadratic_system(Times::SimulationTick, PairSystem::new(|entity, other, _| {
+        println!("Entity:{} and Entity:{} are close!", entity.id(), other.id());
         None
-    });
+    }).with_radius(1.0).unordered());
     */
 
     let to_load = AssetsToLoad {
@@ -1,11 +1,13 @@
+use std::collections::HashMap;
 use std::mem;
 use std::ops::RangeBounds;
 
 use wgpu::{Buffer, BufferAddress, BufferDescriptor, BufferSlice, Device};
 use wgpu::util::{BufferInitDescriptor, DeviceExt};
 
+use crate::game::entity::EntityId;
 use crate::game::GameState;
-use crate::game::transform::{get_pos, RawTransform2D, RawTransform3D};
+use crate::game::transform::{RawTransform2D, RawTransform3D};
 use crate::render::{GPUState, SpriteVertex};
 use crate::util::Either;
 use crate::util::res::Res;
@@ -23,6 +25,9 @@ pub struct AssetStore {
     // instances
     pub instance_buffer_2d: Buffer,
     pub instance_buffer_3d: Buffer,
+    // where each entity's transform is in the instance buffers
+    instances_2d: HashMap<EntityId, u32>,
+    instances_3d: HashMap<EntityId, u32>,
     // quad buffer
     pub quad_vertex_buffer: Buffer,
 }
@@ -45,7 +50,7 @@ impl AssetStore {
             SpriteVertex { position: [0., 1.], tex_coords: [1., 0.] },
         ];
         // constructing the asset store
-        let asset_store = AssetStore {
+        let mut asset_store = AssetStore {
             materials,
             models: vec![],  // models will be populated after
             instance_buffer_2d: gpu.device.create_buffer(&BufferDescriptor {
@@ -60,6 +65,8 @@ impl AssetStore {
                 usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
                 mapped_at_creation: false,
             }),
+            instances_2d: HashMap::new(),
+            instances_3d: HashMap::new(),
             quad_vertex_buffer: gpu.device.create_buffer_init(&BufferInitDescriptor {
                 label: Some("Quad Vertex Buffer"),
                 contents: bytemuck::cast_slice(&SQUARE_MESH),
@@ -76,6 +83,7 @@ impl AssetStore {
                 .expect(&error_str);
             models.push(Res::new(model));
         }
+        asset_store.models = models;
         // wrapping it up
         Res::new(asset_store)
     }
@@ -84,6 +92,10 @@ impl AssetStore {
         self.materials.get(id)
     }
 
+    pub fn get_model(&self, id: usize) -> Option<&Res<Model>> {
+        self.models.get(id)
+    }
+
     pub fn get_material_by_name(&self, material_name: &str) -> Option<&Res<Material>> {
         self.materials.iter()
             .find(|mat| mat.read().unwrap().name == material_name)
@@ -99,6 +111,16 @@ impl AssetStore {
         None
     }
 
+    /// Index of the entity's transform in the 2D instance buffer.
+    pub fn instance_2d(&self, entity: EntityId) -> Option<u32> {
+        self.instances_2d.get(&entity).copied()
+    }
+
+    /// Index of the entity's transform in the 3D instance buffer.
+    pub fn instance_3d(&self, entity: EntityId) -> Option<u32> {
+        self.instances_3d.get(&entity).copied()
+    }
+
     pub fn instance_buffer_2d_slice<S: RangeBounds<BufferAddress>>(&self, range: S) -> BufferSlice<'_> {
         self.instance_buffer_2d.slice(range)
     }
@@ -112,17 +134,27 @@ impl Res<AssetStore> {
     pub fn update_from_game(&mut self, game_state: &GameState, device: &Device) {
         let mut raw2d = Vec::new();
         let mut raw3d = Vec::new();
+        let mut instances_2d = HashMap::new();
+        let mut instances_3d = HashMap::new();
 
-        for entity in game_state.entities.iter() {
-            if let Some(pos) = get_pos(entity.data()) {
+        for entity in game_state.entities() {
+            if let Some(pos) = game_state.interpolated_pos(&entity) {
                 match pos {
-                    Either::This(t_2d) => {raw2d.push(t_2d.to_raw())}
-                    Either::That(t_3d) => {raw3d.push(t_3d.to_raw())}
+                    Either::This(t_2d) => {
+                        instances_2d.insert(entity.id(), raw2d.len() as u32);
+                        raw2d.push(t_2d.to_raw())
+                    }
+                    Either::That(t_3d) => {
+                        instances_3d.insert(entity.id(), raw3d.len() as u32);
+                        raw3d.push(t_3d.to_raw())
+                    }
                 }
             }
         }
         {
             let mut store = self.write().unwrap();
+            store.instances_2d = instances_2d;
+            store.instances_3d = instances_3d;
             store.instance_buffer_2d = device
                 .create_buffer_init(&BufferInitDescriptor {
                     label: Some("2D Instance Buffer"),
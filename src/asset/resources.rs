@@ -5,7 +5,7 @@ use wgpu::{BindGroupLayout, Device, Queue};
 use wgpu::util::DeviceExt;
 
 use crate::asset::model::{Material, Mesh};
-use crate::asset::{AssetStore, model, texture};
+use crate::asset::{model, texture};
 use crate::render::ModelVertex;
 
 #[cfg(target_arch = "wasm32")]
@@ -23,7 +23,7 @@ fn format_url(file_name: &str) -> reqwest::Url {
 }
 
 #[allow(dead_code)]
-const MODEL_DIR: &'static str = "models/";
+const MODEL_DIR: &str = "models/";
 
 #[allow(dead_code)]
 pub async fn load_string(file_name: &str) -> anyhow::Result<String> {
@@ -0,0 +1,401 @@
+use std::mem;
+use std::mem::{ManuallyDrop, MaybeUninit};
+use std::ptr;
+
+use anyhow::anyhow;
+
+use crate::game::component::{uninit_bytes, ComponentKey, ComponentType, ComponentValue};
+use crate::game::entity::{Entity, EntityId};
+
+/// When a component was added to its entity, and when it was last written to,
+/// as change ticks of the [`World`](crate::game::world::World).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ComponentTicks {
+    pub added: u64,
+    pub changed: u64,
+}
+
+impl ComponentTicks {
+    pub fn new(tick: u64) -> Self {
+        ComponentTicks { added: tick, changed: tick }
+    }
+
+    /// Whether the component was added after the given tick.
+    pub fn is_added(&self, since: u64) -> bool {
+        self.added > since
+    }
+
+    /// Whether the component was added or written to after the given tick.
+    pub fn is_changed(&self, since: u64) -> bool {
+        self.changed > since
+    }
+}
+
+/// Every component stored under one key, laid out contiguously.
+/// Row `i` of the column belongs to the `i`th entity of the owning [`Archetype`].
+/// The column owns the values it stores, and drops them along with itself.
+pub struct Column {
+    ty: ComponentType,
+    data: Vec<MaybeUninit<u8>>,
+    ticks: Vec<ComponentTicks>,
+}
+
+impl Column {
+    pub fn new(ty: ComponentType) -> Self {
+        Column {
+            ty,
+            data: Vec::new(),
+            ticks: Vec::new(),
+        }
+    }
+
+    pub fn ty(&self) -> &ComponentType {
+        &self.ty
+    }
+
+    pub fn item_size(&self) -> usize {
+        self.ty.size()
+    }
+
+    /// The bytes of a row, to be read as the type of the column.
+    pub fn get_bytes(&self, row: usize) -> Option<&[MaybeUninit<u8>]> {
+        let start = row * self.item_size();
+        self.data.get(start..start + self.item_size())
+    }
+
+    pub fn get_ticks(&self, row: usize) -> Option<ComponentTicks> {
+        self.ticks.get(row).copied()
+    }
+
+    /// Replaces the value of a row, dropping the old one.
+    fn set(&mut self, row: usize, value: ComponentValue, tick: u64) {
+        let size = self.item_size();
+        let dest = &mut self.data[row * size..(row + 1) * size];
+        unsafe { self.ty.drop_in_place(dest) };
+        dest.copy_from_slice(&value.into_raw());
+        self.ticks[row].changed = tick;
+    }
+
+    fn push(&mut self, value: ComponentValue, ticks: ComponentTicks) {
+        debug_assert_eq!(value.ty().type_id(), self.ty.type_id());
+        self.data.extend_from_slice(&value.into_raw());
+        self.ticks.push(ticks);
+    }
+
+    /// Moves the value of `row` out, moves the last row into its place and shrinks the column by one.
+    fn swap_remove(&mut self, row: usize, last: usize) -> (ComponentValue, ComponentTicks) {
+        let size = self.item_size();
+        let value = self.data[row * size..(row + 1) * size].to_vec();
+        if row != last {
+            self.data.copy_within(last * size..(last + 1) * size, row * size);
+        }
+        self.data.truncate(last * size);
+        let ticks = self.ticks.swap_remove(row);
+        (unsafe { ComponentValue::from_raw(self.ty, value) }, ticks)
+    }
+}
+
+impl Clone for Column {
+    fn clone(&self) -> Self {
+        let mut data = uninit_bytes(self.data.len());
+        let size = self.item_size();
+        for row in 0..self.ticks.len() {
+            let rows = row * size..(row + 1) * size;
+            unsafe { self.ty.clone_into(&self.data[rows.clone()], &mut data[rows]) };
+        }
+        Column { ty: self.ty, data, ticks: self.ticks.clone() }
+    }
+}
+
+impl Drop for Column {
+    fn drop(&mut self) {
+        // one value per row, even for zero-sized types
+        let size = self.item_size();
+        if self.ty.needs_drop() {
+            for row in 0..self.ticks.len() {
+                unsafe { self.ty.drop_in_place(&mut self.data[row * size..(row + 1) * size]) };
+            }
+        }
+    }
+}
+
+/// Identifies an archetype: the sorted keys of its components.
+pub type ArchetypeKey = Vec<ComponentKey>;
+
+/// Table of all the entities that have exactly the same set of components.
+/// Each component key gets its own [`Column`], so systems iterating over
+/// one kind of component walk contiguous memory.
+#[derive(Clone)]
+pub struct Archetype {
+    key: ArchetypeKey,
+    columns: Vec<Column>,
+    entities: Vec<EntityId>,
+}
+
+impl Archetype {
+    /// Creates an empty archetype from its component keys and their types.
+    /// Panics if a type is not the one its key is for.
+    pub fn new(mut components: Vec<(ComponentKey, ComponentType)>) -> Self {
+        for (key, ty) in &components {
+            assert_eq!(key.type_id(), ty.type_id(), "a column of {:?} cannot hold {}", ty, key);
+        }
+        components.sort_by(|(k1, _), (k2, _)| k1.cmp(k2));
+        let (key, columns) = components.into_iter()
+            .map(|(key, ty)| (key, Column::new(ty)))
+            .unzip();
+        Archetype {
+            key,
+            columns,
+            entities: Vec::new(),
+        }
+    }
+
+    pub fn key(&self) -> &ArchetypeKey {
+        &self.key
+    }
+
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    pub fn entities(&self) -> &[EntityId] {
+        &self.entities
+    }
+
+    /// Iterates over the entities of this archetype, in row order.
+    pub fn iter(&self) -> impl Iterator<Item = Entity<'_>> {
+        self.entities.iter().enumerate()
+            .map(move |(row, id)| Entity::new(*id, self, row))
+    }
+
+    pub fn column_index(&self, key: &ComponentKey) -> Option<usize> {
+        self.key.binary_search(key).ok()
+    }
+
+    pub fn has(&self, key: &ComponentKey) -> bool {
+        self.column_index(key).is_some()
+    }
+
+    pub fn column(&self, key: &ComponentKey) -> Option<&Column> {
+        Some(&self.columns[self.column_index(key)?])
+    }
+
+    pub fn get_bytes(&self, row: usize, key: &ComponentKey) -> Option<&[MaybeUninit<u8>]> {
+        self.columns[self.column_index(key)?].get_bytes(row)
+    }
+
+    pub fn get_ticks(&self, row: usize, key: &ComponentKey) -> Option<ComponentTicks> {
+        self.columns[self.column_index(key)?].get_ticks(row)
+    }
+
+    /// Replaces a component of a row, dropping the old value, and marks it as changed at `tick`.
+    pub fn set(&mut self, row: usize, key: &ComponentKey, value: ComponentValue, tick: u64) -> anyhow::Result<()> {
+        let i = self.column_index(key)
+            .ok_or_else(|| anyhow!("no component {} in this archetype!", key))?;
+        let column = &mut self.columns[i];
+        if value.ty().type_id() != key.type_id() || value.ty().size() != column.item_size() {
+            return Err(anyhow!("a {:?} cannot be stored as {}!", value.ty(), key));
+        }
+        if row >= self.entities.len() {
+            return Err(anyhow!("row {} is out of bounds!", row));
+        }
+        column.set(row, value, tick);
+        Ok(())
+    }
+
+    /// Appends a row. `components` must hold a value of the right type for every key of the archetype.
+    /// Returns the row the entity was placed in.
+    pub fn push(&mut self, id: EntityId, mut components: Vec<(ComponentKey, ComponentValue, ComponentTicks)>) -> usize {
+        components.sort_by(|(k1, ..), (k2, ..)| k1.cmp(k2));
+        assert!(
+            components.iter().map(|(key, ..)| key).eq(self.key.iter()),
+            "components don't match the archetype they are moved into",
+        );
+        // checked before anything is pushed, so a panic leaves every column the same length
+        for ((key, value, _), column) in components.iter().zip(&self.columns) {
+            assert!(
+                value.ty().type_id() == key.type_id() && value.ty().size() == column.item_size(),
+                "a {:?} cannot be stored as {}", value.ty(), key,
+            );
+        }
+        for ((_, value, ticks), column) in components.into_iter().zip(self.columns.iter_mut()) {
+            column.push(value, ticks);
+        }
+        self.entities.push(id);
+        self.entities.len() - 1
+    }
+
+    /// Removes a row by swapping the last one into its place, and hands back its components.
+    /// Also returns the id of the entity that was moved into `row`, if any.
+    pub fn take(&mut self, row: usize) -> (Vec<(ComponentKey, ComponentValue, ComponentTicks)>, Option<EntityId>) {
+        let last = self.entities.len() - 1;
+        let components = self.key.iter().cloned()
+            .zip(self.columns.iter_mut())
+            .map(|(key, column)| {
+                let (value, ticks) = column.swap_remove(row, last);
+                (key, value, ticks)
+            })
+            .collect();
+        self.entities.swap_remove(row);
+        let moved = (row != last).then(|| self.entities[row]);
+        (components, moved)
+    }
+
+    /// Removes a row by swapping the last one into its place, dropping its components.
+    /// Returns the id of the entity that was moved into `row`, if any.
+    pub fn swap_remove(&mut self, row: usize) -> Option<EntityId> {
+        self.take(row).1
+    }
+}
+
+/// Reads a `T` out of component bytes without taking ownership of the stored value.
+pub(crate) fn read_component<T: Clone>(bytes: &[MaybeUninit<u8>]) -> Option<T> {
+    if bytes.len() != mem::size_of::<T>() {
+        return None;
+    }
+    let value = unsafe {
+        ManuallyDrop::new(ptr::read_unaligned(bytes.as_ptr() as *const T))
+    };
+    Some((*value).clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use crate::game::archetype::*;
+
+    #[test]
+    fn push_and_read_rows() {
+        let (a, b) = (ComponentKey::of::<u64>(), ComponentKey::of::<u32>());
+        let mut arch = Archetype::new(vec![(b.clone(), ComponentType::of::<u32>()), (a.clone(), ComponentType::of::<u64>())]);
+        let row = |x: u64, y: u32| vec![
+            (a.clone(), ComponentValue::new(x), ComponentTicks::new(1)),
+            (b.clone(), ComponentValue::new(y), ComponentTicks::new(1)),
+        ];
+        arch.push(EntityId::new(10, 0), row(7, 3));
+        arch.push(EntityId::new(11, 0), row(9, 5));
+
+        assert_eq!(arch.len(), 2);
+        assert!(arch.has(&a) && arch.has(&b));
+        assert!(!arch.has(&ComponentKey::labelled::<u32>("other")));
+        assert_eq!(read_component::<u64>(arch.get_bytes(1, &a).unwrap()), Some(9));
+        assert_eq!(read_component::<u32>(arch.get_bytes(0, &b).unwrap()), Some(3));
+        // wrong size is rejected
+        assert_eq!(read_component::<u32>(arch.get_bytes(0, &a).unwrap()), None);
+        // and so is the wrong type
+        assert!(arch.set(0, &b, ComponentValue::new(1f32), 2).is_err());
+        arch.set(0, &b, ComponentValue::new(4u32), 2).unwrap();
+        assert_eq!(read_component::<u32>(arch.get_bytes(0, &b).unwrap()), Some(4));
+        assert_eq!(arch.get_ticks(0, &b), Some(ComponentTicks { added: 1, changed: 2 }));
+        assert_eq!(arch.get_ticks(1, &b), Some(ComponentTicks::new(1)));
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot be stored")]
+    fn values_of_the_wrong_type_are_rejected() {
+        let key = ComponentKey::of::<String>();
+        let mut arch = Archetype::new(vec![(key.clone(), ComponentType::of::<String>())]);
+        arch.push(EntityId::new(0, 0), vec![(key, ComponentValue::new([0x41414141u64; 3]), ComponentTicks::new(0))]);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot hold")]
+    fn columns_must_match_their_key() {
+        Archetype::new(vec![(ComponentKey::of::<String>(), ComponentType::of::<[u64; 3]>())]);
+    }
+
+    #[test]
+    fn swap_remove_moves_last_row() {
+        let key = ComponentKey::of::<u32>();
+        let mut arch = Archetype::new(vec![(key.clone(), ComponentType::of::<u32>())]);
+        for i in 0..3u32 {
+            arch.push(EntityId::new(i, 0), vec![(key.clone(), ComponentValue::new(i), ComponentTicks::new(i as u64))]);
+        }
+        assert_eq!(arch.swap_remove(0), Some(EntityId::new(2, 0)));
+        assert_eq!(arch.entities(), &[EntityId::new(2, 0), EntityId::new(1, 0)]);
+        assert_eq!(read_component::<u32>(arch.get_bytes(0, &key).unwrap()), Some(2));
+        assert_eq!(arch.get_ticks(0, &key), Some(ComponentTicks::new(2)));
+        assert_eq!(arch.swap_remove(1), None);
+        assert_eq!(arch.entities(), &[EntityId::new(2, 0)]);
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    struct Padded {
+        a: u8,
+        b: u32,
+    }
+
+    #[test]
+    fn values_with_padding_are_stored() {
+        let key = ComponentKey::of::<Padded>();
+        let mut arch = Archetype::new(vec![(key.clone(), ComponentType::of::<Padded>())]);
+        for i in 0..3 {
+            arch.push(EntityId::new(i, 0), vec![(key.clone(), ComponentValue::new(Padded { a: i as u8, b: i }), ComponentTicks::new(0))]);
+        }
+        let copy = arch.clone();
+        arch.swap_remove(0);
+        assert_eq!(read_component::<Padded>(arch.get_bytes(0, &key).unwrap()), Some(Padded { a: 2, b: 2 }));
+        assert_eq!(read_component::<Padded>(copy.get_bytes(0, &key).unwrap()), Some(Padded { a: 0, b: 0 }));
+        assert_eq!(ComponentValue::new(Padded { a: 1, b: 1 }).raw_bytes(), None);
+    }
+
+    #[test]
+    fn zero_sized_values_are_cloned_and_dropped() {
+        static CLONES: AtomicUsize = AtomicUsize::new(0);
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct Marker;
+        impl Clone for Marker {
+            fn clone(&self) -> Self {
+                CLONES.fetch_add(1, Ordering::Relaxed);
+                Marker
+            }
+        }
+        impl Drop for Marker {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let key = ComponentKey::of::<Marker>();
+        let mut arch = Archetype::new(vec![(key.clone(), ComponentType::of::<Marker>())]);
+        for i in 0..3 {
+            arch.push(EntityId::new(i, 0), vec![(key.clone(), ComponentValue::new(Marker), ComponentTicks::new(0))]);
+        }
+        let copy = arch.clone();
+        assert_eq!(CLONES.load(Ordering::Relaxed), 3);
+        drop(copy);
+        drop(arch);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 6);
+    }
+
+    #[test]
+    fn stored_values_are_cloned_and_dropped() {
+        let key = ComponentKey::of::<Arc<String>>();
+        let shared = Arc::new("hat".to_string());
+        let mut arch = Archetype::new(vec![(key.clone(), ComponentType::of::<Arc<String>>())]);
+        for i in 0..3 {
+            arch.push(EntityId::new(i, 0), vec![(key.clone(), ComponentValue::new(shared.clone()), ComponentTicks::new(0))]);
+        }
+        assert_eq!(Arc::strong_count(&shared), 4);
+
+        let copy = arch.clone();
+        assert_eq!(Arc::strong_count(&shared), 7);
+        drop(copy);
+        arch.swap_remove(0);
+        let (mut components, _) = arch.take(0);
+        let taken = components.pop().unwrap().1.downcast::<Arc<String>>().unwrap();
+        assert_eq!(*taken, "hat");
+        assert_eq!(Arc::strong_count(&shared), 3);
+        arch.set(0, &key, ComponentValue::new(Arc::new("cap".to_string())), 1).unwrap();
+        assert_eq!(Arc::strong_count(&shared), 2);
+        drop(arch);
+        drop(taken);
+        assert_eq!(Arc::strong_count(&shared), 1);
+    }
+}
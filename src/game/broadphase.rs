@@ -0,0 +1,230 @@
+use std::any::type_name;
+use std::collections::HashMap;
+
+use crate::game::entity::{Entity, EntityChange, EntityData};
+use crate::game::resource::Resources;
+use crate::game::transform::get_world_pos;
+use crate::game::QuadraticSystem;
+use crate::util::Either;
+
+const MIN_CELL_SIZE: f32 = 1e-3;
+
+/// Which pairs of entities a quadratic system needs to see.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Bounds {
+    /// Every pair is visited.
+    All,
+    /// Only pairs whose transforms are at most this far apart.
+    Radius(f32),
+    /// Only pairs whose transforms are at most this far apart on every axis.
+    Aabb([f32; 3]),
+}
+
+impl Bounds {
+    /// Whether two positions are close enough to be visited.
+    pub fn contains(&self, a: [f32; 3], b: [f32; 3]) -> bool {
+        let d = [b[0] - a[0], b[1] - a[1], b[2] - a[2]];
+        match self {
+            Bounds::All => true,
+            Bounds::Radius(r) => d[0] * d[0] + d[1] * d[1] + d[2] * d[2] <= r * r,
+            Bounds::Aabb(half) => (0..3).all(|i| d[i].abs() <= half[i]),
+        }
+    }
+
+    /// Size of the spatial hash cells, so that candidates are always in neighbouring cells.
+    /// Tiny bounds get bigger cells than needed, which only adds candidates that are then filtered out.
+    fn cell_size(&self) -> Option<f32> {
+        match self {
+            Bounds::All => None,
+            Bounds::Radius(r) => Some(r.max(MIN_CELL_SIZE)),
+            Bounds::Aabb(half) => Some(half[0].max(half[1]).max(half[2]).max(MIN_CELL_SIZE)),
+        }
+    }
+}
+
+/// Which entity of the pair the changes of a quadratic system are for.
+pub enum PairChange {
+    /// The entity the system is run for.
+    This(Box<dyn EntityChange>),
+    /// The other entity of the pair.
+    Other(Box<dyn EntityChange>),
+    /// One change for each: this entity first, then the other.
+    Both(Box<dyn EntityChange>, Box<dyn EntityChange>),
+}
+
+/// A quadratic system, along with how close two entities must be for it to run on them.
+///
+/// With [`Bounds::All`] the system runs on every pair.
+/// Otherwise only entities with a `Transform2D` or `Transform3D` take part, and pairs
+/// outside the bounds are skipped without calling the system. Systems that ignore
+/// far away pairs give the same results either way.
+///
+/// By default the system runs on both `(a, b)` and `(b, a)`. Symmetric systems can
+/// be made [`unordered`](PairSystem::unordered) and emit [`PairChange::Both`] instead.
+pub struct PairSystem {
+    pub name: String,
+    pub system: QuadraticSystem,
+    pub bounds: Bounds,
+    /// Visit every unordered pair once, instead of once per order
+    pub unordered: bool,
+}
+
+impl PairSystem {
+    pub fn new<F>(system: F) -> Self
+    where
+        F: Fn(&Entity, &Entity, &Resources) -> Option<PairChange> + Send + Sync + 'static,
+    {
+        PairSystem {
+            name: type_name::<F>().to_string(),
+            system: Box::new(system),
+            bounds: Bounds::All,
+            unordered: false,
+        }
+    }
+
+    pub fn unordered(mut self) -> Self {
+        self.unordered = true;
+        self
+    }
+
+    /// Panics if the radius is negative or NaN.
+    pub fn with_radius(mut self, radius: f32) -> Self {
+        assert!(radius >= 0., "the radius of {} must be positive, not {}", self.name, radius);
+        self.bounds = Bounds::Radius(radius);
+        self
+    }
+
+    /// Panics if one of the half extents is negative or NaN.
+    pub fn with_aabb(mut self, half_extents: [f32; 3]) -> Self {
+        assert!(half_extents.iter().all(|h| *h >= 0.), "the half extents of {} must be positive, not {:?}", self.name, half_extents);
+        self.bounds = Bounds::Aabb(half_extents);
+        self
+    }
+}
+
+impl<F> From<F> for PairSystem
+where
+    F: Fn(&Entity, &Entity, &Resources) -> Option<PairChange> + Send + Sync + 'static,
+{
+    fn from(system: F) -> Self {
+        PairSystem::new(system)
+    }
+}
+
+/// Position of an entity in the world, if it has a transform. 2D transforms sit at z = 0.
+pub fn position(data: &EntityData) -> Option<[f32; 3]> {
+    match get_world_pos(data)? {
+        Either::This(t) => Some([t.pos[0], t.pos[1], 0.]),
+        Either::That(t) => Some(t.pos),
+    }
+}
+
+/// Buckets entity positions into a uniform grid to find nearby pairs quickly.
+pub struct SpatialHash {
+    bounds: Bounds,
+    cell_size: f32,
+    cells: HashMap<[i64; 3], Vec<usize>>,
+}
+
+impl SpatialHash {
+    /// Builds the grid for `bounds`, or returns None if every pair must be visited anyway.
+    /// `positions` are indexed the same way as the entities the candidates refer to.
+    pub fn new(bounds: Bounds, positions: &[Option<[f32; 3]>]) -> Option<Self> {
+        let cell_size = bounds.cell_size()?;
+        let mut hash = SpatialHash {
+            bounds,
+            cell_size,
+            cells: HashMap::new(),
+        };
+        for (i, pos) in positions.iter().enumerate() {
+            if let Some(pos) = pos {
+                hash.cells.entry(hash.cell(*pos)).or_default().push(i);
+            }
+        }
+        Some(hash)
+    }
+
+    /// Positions too far out to be counted in cells share the outermost ones.
+    fn cell(&self, pos: [f32; 3]) -> [i64; 3] {
+        pos.map(|x| (x / self.cell_size).floor() as i64)
+    }
+
+    /// Indices of the entities within bounds of `pos`, in ascending order
+    /// (the same order the brute-force path visits them in).
+    pub fn candidates(&self, pos: [f32; 3], positions: &[Option<[f32; 3]>]) -> Vec<usize> {
+        let [x, y, z] = self.cell(pos);
+        let mut out = Vec::new();
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                for dz in -1..=1 {
+                    let cell = [x.saturating_add(dx), y.saturating_add(dy), z.saturating_add(dz)];
+                    let Some(cell) = self.cells.get(&cell) else {
+                        continue;
+                    };
+                    out.extend(cell.iter().copied().filter(|i| {
+                        positions[*i].is_some_and(|other| self.bounds.contains(pos, other))
+                    }));
+                }
+            }
+        }
+        out.sort_unstable();
+        // the outermost cells are their own neighbours
+        out.dedup();
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::game::broadphase::*;
+
+    #[test]
+    fn candidates_match_brute_force() {
+        let positions: Vec<Option<[f32; 3]>> = (0..200)
+            .map(|i| {
+                if i % 17 == 0 {
+                    return None;
+                }
+                let f = i as f32;
+                Some([(f * 0.37).sin() * 5., (f * 0.73).cos() * 5., (i % 3) as f32])
+            })
+            .collect();
+
+        for bounds in [Bounds::Radius(1.3), Bounds::Aabb([0.5, 2., 1.])] {
+            let hash = SpatialHash::new(bounds, &positions).unwrap();
+            for pos in positions.iter().flatten() {
+                let brute: Vec<usize> = (0..positions.len())
+                    .filter(|i| positions[*i].is_some_and(|other| bounds.contains(*pos, other)))
+                    .collect();
+                assert_eq!(hash.candidates(*pos, &positions), brute);
+            }
+        }
+        assert!(SpatialHash::new(Bounds::All, &positions).is_none());
+    }
+
+    #[test]
+    fn tiny_bounds_and_far_positions() {
+        let positions = vec![
+            Some([1000., 0., 0.]),
+            Some([1000., 0., 0.]),
+            Some([f32::MAX, 0., 0.]),
+            Some([f32::MAX, f32::MIN, 0.]),
+            Some([f32::INFINITY, 0., 0.]),
+        ];
+        for bounds in [Bounds::Radius(0.), Bounds::Aabb([0., 1e-30, 0.])] {
+            let hash = SpatialHash::new(bounds, &positions).unwrap();
+            for pos in positions.iter().flatten() {
+                let brute: Vec<usize> = (0..positions.len())
+                    .filter(|i| positions[*i].is_some_and(|other| bounds.contains(*pos, other)))
+                    .collect();
+                assert_eq!(hash.candidates(*pos, &positions), brute);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "must be positive")]
+    fn negative_radii_are_rejected() {
+        PairSystem::new(|_: &Entity, _: &Entity, _: &Resources| None).with_radius(-1.);
+    }
+}
@@ -0,0 +1,576 @@
+use std::any::{type_name, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::Debug;
+use std::mem::{self, ManuallyDrop, MaybeUninit};
+
+use anyhow::anyhow;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::game::entity::{Entity, EntityData, EntityId};
+use crate::game::world::World;
+
+/// Identifies a component of an entity: its Rust type, plus an optional label
+/// for entities that need more than one component of the same type.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ComponentKey {
+    type_id: TypeId,
+    label: Option<String>,
+    type_name: &'static str,
+}
+
+impl ComponentKey {
+    pub fn of<T: 'static>() -> Self {
+        ComponentKey {
+            type_id: TypeId::of::<T>(),
+            label: None,
+            type_name: type_name::<T>(),
+        }
+    }
+
+    pub fn labelled<T: 'static>(label: &str) -> Self {
+        ComponentKey {
+            label: Some(label.to_string()),
+            ..Self::of::<T>()
+        }
+    }
+
+    pub(crate) fn new<T: 'static>(label: Option<&str>) -> Self {
+        match label {
+            None => Self::of::<T>(),
+            Some(label) => Self::labelled::<T>(label),
+        }
+    }
+
+    pub fn type_id(&self) -> TypeId {
+        self.type_id
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        self.type_name
+    }
+
+    pub fn is<T: 'static>(&self) -> bool {
+        self.type_id == TypeId::of::<T>()
+    }
+
+    /// The key of the same component type, with another label.
+    pub fn with_label(&self, label: Option<&str>) -> Self {
+        ComponentKey {
+            label: label.map(str::to_string),
+            ..self.clone()
+        }
+    }
+}
+
+impl fmt::Display for ComponentKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.label {
+            None => write!(f, "{}", self.type_name),
+            Some(label) => write!(f, "{}[{}]", self.type_name, label),
+        }
+    }
+}
+
+/// Storage for `len` bytes that don't hold anything yet.
+pub(crate) fn uninit_bytes(len: usize) -> Vec<MaybeUninit<u8>> {
+    vec![MaybeUninit::uninit(); len]
+}
+
+/// Views bytes known to be initialized, such as the ones of a raw component.
+///
+/// # Safety
+/// Every byte must be initialized. This is not the case for Rust values with padding.
+pub(crate) unsafe fn assume_init(bytes: &[MaybeUninit<u8>]) -> &[u8] {
+    &*(bytes as *const [MaybeUninit<u8>] as *const [u8])
+}
+
+/// How to handle the values of a type that is only known as bytes.
+/// Every stored component carries one, so it is cloned and dropped like the Rust value it holds.
+///
+/// The bytes are `MaybeUninit<u8>`, as the padding of a value is not initialized,
+/// and are only ever read back as the type they hold.
+#[derive(Copy, Clone)]
+pub struct ComponentType {
+    type_id: TypeId,
+    type_name: &'static str,
+    size: usize,
+    align: usize,
+    // None if the type has nothing to drop
+    drop: Option<unsafe fn(&mut [MaybeUninit<u8>])>,
+    clone: unsafe fn(&[MaybeUninit<u8>], &mut [MaybeUninit<u8>]),
+}
+
+impl ComponentType {
+    pub fn of<T: Clone + Send + Sync + 'static>() -> Self {
+        ComponentType {
+            type_id: TypeId::of::<T>(),
+            type_name: type_name::<T>(),
+            size: mem::size_of::<T>(),
+            align: mem::align_of::<T>(),
+            drop: mem::needs_drop::<T>().then_some(|bytes: &mut [MaybeUninit<u8>]| unsafe {
+                (bytes.as_mut_ptr() as *mut T).read_unaligned();
+            }),
+            clone: |src, dst| unsafe {
+                let value = (*read::<T>(src)).clone();
+                (dst.as_mut_ptr() as *mut T).write_unaligned(value);
+            },
+        }
+    }
+
+    /// Plain bytes of the given length, which need no dropping and are cloned by copying them.
+    pub fn raw(size: usize) -> Self {
+        ComponentType {
+            type_id: TypeId::of::<[u8]>(),
+            type_name: "raw bytes",
+            size,
+            align: 1,
+            drop: None,
+            clone: |src, dst| dst.copy_from_slice(src),
+        }
+    }
+
+    pub fn type_id(&self) -> TypeId {
+        self.type_id
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        self.type_name
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Alignment of the Rust type. Components are stored unaligned, and read with unaligned loads.
+    pub fn align(&self) -> usize {
+        self.align
+    }
+
+    pub fn is<T: 'static>(&self) -> bool {
+        self.type_id == TypeId::of::<T>()
+    }
+
+    /// Whether these are plain bytes, made with [`ComponentType::raw`].
+    pub fn is_raw(&self) -> bool {
+        self.type_id == TypeId::of::<[u8]>()
+    }
+
+    pub fn needs_drop(&self) -> bool {
+        self.drop.is_some()
+    }
+
+    /// Drops the value held by the bytes.
+    ///
+    /// # Safety
+    /// The bytes must hold a value of this type, which must not be used afterwards.
+    pub(crate) unsafe fn drop_in_place(&self, bytes: &mut [MaybeUninit<u8>]) {
+        debug_assert_eq!(bytes.len(), self.size);
+        if let Some(drop) = self.drop {
+            drop(bytes);
+        }
+    }
+
+    /// Writes a clone of the value held by `src` into `dst`, without dropping what `dst` held.
+    ///
+    /// # Safety
+    /// `src` must hold a value of this type, and both must be as long as the type.
+    pub(crate) unsafe fn clone_into(&self, src: &[MaybeUninit<u8>], dst: &mut [MaybeUninit<u8>]) {
+        debug_assert!(src.len() == self.size && dst.len() == self.size);
+        (self.clone)(src, dst)
+    }
+}
+
+impl fmt::Debug for ComponentType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({} bytes)", self.type_name, self.size)
+    }
+}
+
+/// A component value moved into bytes, along with its type.
+/// It owns the value: dropping or cloning it drops or clones the value.
+///
+/// Only `Send + Sync` values can be stored, which is what makes the world safe to share between threads:
+///
+/// ```compile_fail
+/// use std::rc::Rc;
+/// use functional_game_engine::game::component::ComponentValue;
+///
+/// ComponentValue::new(Rc::new(0));
+/// ```
+pub struct ComponentValue {
+    ty: ComponentType,
+    bytes: Vec<MaybeUninit<u8>>,
+}
+
+impl ComponentValue {
+    pub fn new<T: Clone + Send + Sync + 'static>(value: T) -> Self {
+        let mut bytes = uninit_bytes(mem::size_of::<T>());
+        unsafe { (bytes.as_mut_ptr() as *mut T).write_unaligned(value) };
+        ComponentValue {
+            ty: ComponentType::of::<T>(),
+            bytes,
+        }
+    }
+
+    /// Plain bytes, with no Rust value behind them.
+    pub fn raw(bytes: &[u8]) -> Self {
+        ComponentValue {
+            ty: ComponentType::raw(bytes.len()),
+            bytes: bytes.iter().copied().map(MaybeUninit::new).collect(),
+        }
+    }
+
+    /// Takes ownership of a value moved into bytes.
+    ///
+    /// # Safety
+    /// The bytes must hold a value of the type, which nothing else owns.
+    pub(crate) unsafe fn from_raw(ty: ComponentType, bytes: Vec<MaybeUninit<u8>>) -> Self {
+        debug_assert_eq!(bytes.len(), ty.size);
+        ComponentValue { ty, bytes }
+    }
+
+    pub fn ty(&self) -> &ComponentType {
+        &self.ty
+    }
+
+    pub fn bytes(&self) -> &[MaybeUninit<u8>] {
+        &self.bytes
+    }
+
+    /// The bytes of a raw component. Values of Rust types can't be viewed as plain bytes.
+    pub fn raw_bytes(&self) -> Option<&[u8]> {
+        self.ty.is_raw().then(|| unsafe { assume_init(&self.bytes) })
+    }
+
+    /// Gives up ownership of the value: the caller becomes responsible for dropping what the bytes hold.
+    pub(crate) fn into_raw(self) -> Vec<MaybeUninit<u8>> {
+        let mut this = ManuallyDrop::new(self);
+        mem::take(&mut this.bytes)
+    }
+
+    /// Moves the value back out, if it is a `T`.
+    pub fn downcast<T: 'static>(self) -> Result<T, Self> {
+        if !self.ty.is::<T>() {
+            return Err(self);
+        }
+        let bytes = self.into_raw();
+        Ok(unsafe { (bytes.as_ptr() as *const T).read_unaligned() })
+    }
+}
+
+impl Clone for ComponentValue {
+    fn clone(&self) -> Self {
+        let mut bytes = uninit_bytes(self.bytes.len());
+        unsafe {
+            self.ty.clone_into(&self.bytes, &mut bytes);
+            Self::from_raw(self.ty, bytes)
+        }
+    }
+}
+
+impl Drop for ComponentValue {
+    fn drop(&mut self) {
+        unsafe { self.ty.drop_in_place(&mut self.bytes) }
+    }
+}
+
+impl fmt::Debug for ComponentValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ComponentValue({:?})", self.ty)
+    }
+}
+
+type SerializeFn = fn(&[MaybeUninit<u8>]) -> serde_json::Result<Value>;
+type DeserializeFn = fn(Value) -> serde_json::Result<ComponentValue>;
+
+/// Reads a component out of its bytes. The bytes keep owning whatever the component points to.
+///
+/// # Safety
+/// The bytes must hold a `T`.
+unsafe fn read<T>(bytes: &[MaybeUninit<u8>]) -> ManuallyDrop<T> {
+    assert_eq!(bytes.len(), mem::size_of::<T>(), "the bytes do not hold a {}", type_name::<T>());
+    // components are stored unaligned
+    ManuallyDrop::new((bytes.as_ptr() as *const T).read_unaligned())
+}
+
+/// What the registry knows about a component type, so it can be handled without knowing the type.
+#[derive(Clone)]
+pub struct ComponentInfo {
+    name: String,
+    key: ComponentKey,
+    ty: ComponentType,
+    debug: fn(&[MaybeUninit<u8>], &mut fmt::Formatter<'_>) -> fmt::Result,
+    serde: Option<(SerializeFn, DeserializeFn)>,
+}
+
+impl ComponentInfo {
+    fn new<T: Clone + Debug + Send + Sync + 'static>(name: &str) -> Self {
+        ComponentInfo {
+            name: name.to_string(),
+            key: ComponentKey::of::<T>(),
+            ty: ComponentType::of::<T>(),
+            debug: |bytes, f| T::fmt(&*unsafe { read::<T>(bytes) }, f),
+            serde: None,
+        }
+    }
+
+    /// The name the component is registered, printed and saved under.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The key of the component, without a label.
+    pub fn key(&self) -> &ComponentKey {
+        &self.key
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        self.key.type_name()
+    }
+
+    /// How values of the component are cloned and dropped.
+    pub fn ty(&self) -> &ComponentType {
+        &self.ty
+    }
+
+    pub fn size(&self) -> usize {
+        self.ty.size()
+    }
+
+    pub fn align(&self) -> usize {
+        self.ty.align()
+    }
+
+    /// Whether the component can be turned into JSON and back.
+    pub fn has_serde(&self) -> bool {
+        self.serde.is_some()
+    }
+
+    /// The bytes of a component of the entity, if it has one under that key and it is of this type.
+    fn bytes_in<'a>(&self, data: &EntityData<'a>, key: &ComponentKey) -> Option<&'a [MaybeUninit<u8>]> {
+        let column = data.archetype().column(key)?;
+        if key.type_id() != self.key.type_id() || column.ty().type_id() != self.key.type_id() {
+            return None;
+        }
+        data.get_bytes(key)
+    }
+
+    /// Formats a component of an entity with its `Debug` impl.
+    /// Returns `None` if the entity has no component of this type under the key.
+    pub fn format(&self, data: &EntityData, key: &ComponentKey) -> Option<String> {
+        struct Bytes<'a>(&'a [MaybeUninit<u8>], fn(&[MaybeUninit<u8>], &mut fmt::Formatter<'_>) -> fmt::Result);
+        impl fmt::Debug for Bytes<'_> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                (self.1)(self.0, f)
+            }
+        }
+        Some(format!("{:?}", Bytes(self.bytes_in(data, key)?, self.debug)))
+    }
+
+    /// Turns a component of an entity into JSON.
+    pub fn serialize(&self, data: &EntityData, key: &ComponentKey) -> anyhow::Result<Value> {
+        let (serialize, _) = self.serde
+            .ok_or_else(|| anyhow!("component {} was registered without serde", self.name))?;
+        let bytes = self.bytes_in(data, key)
+            .ok_or_else(|| anyhow!("the entity has no {} under {}", self.name, key))?;
+        Ok(serialize(bytes)?)
+    }
+
+    /// Turns JSON back into a component, ready to be stored in an entity.
+    pub fn deserialize(&self, value: Value) -> anyhow::Result<ComponentValue> {
+        let (_, deserialize) = self.serde
+            .ok_or_else(|| anyhow!("component {} was registered without serde", self.name))?;
+        Ok(deserialize(value)?)
+    }
+}
+
+/// Maps component types to names and to what is needed to handle them at runtime:
+/// their layout, a `Debug` formatter and optionally serde functions.
+/// Tools use it to list, print and edit the components of any entity,
+/// and only components registered with serde can be saved to or loaded from a scene.
+#[derive(Clone, Default)]
+pub struct ComponentRegistry {
+    infos: Vec<ComponentInfo>,
+    by_type: HashMap<TypeId, usize>,
+    by_name: HashMap<String, usize>,
+}
+
+impl ComponentRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `T` under the given name. Registering a type or a name again replaces it.
+    pub fn register<T: Clone + Debug + Send + Sync + 'static>(&mut self, name: &str) -> &mut Self {
+        self.add(ComponentInfo::new::<T>(name))
+    }
+
+    /// Registers `T` like [`ComponentRegistry::register`], so it can also be saved and loaded.
+    pub fn register_serde<T>(&mut self, name: &str) -> &mut Self
+    where
+        T: Serialize + DeserializeOwned + Clone + Debug + Send + Sync + 'static,
+    {
+        let serialize: SerializeFn = |bytes| serde_json::to_value(&*unsafe { read::<T>(bytes) });
+        let deserialize: DeserializeFn = |value| Ok(ComponentValue::new(serde_json::from_value::<T>(value)?));
+        self.add(ComponentInfo {
+            serde: Some((serialize, deserialize)),
+            ..ComponentInfo::new::<T>(name)
+        })
+    }
+
+    fn add(&mut self, info: ComponentInfo) -> &mut Self {
+        if let Some(i) = self.by_type.get(&info.key.type_id()).copied() {
+            self.remove_at(i);
+        }
+        if let Some(i) = self.by_name.get(&info.name).copied() {
+            self.remove_at(i);
+        }
+        self.by_type.insert(info.key.type_id(), self.infos.len());
+        self.by_name.insert(info.name.clone(), self.infos.len());
+        self.infos.push(info);
+        self
+    }
+
+    fn remove_at(&mut self, i: usize) {
+        let info = self.infos.remove(i);
+        self.by_name.remove(&info.name);
+        self.by_type.remove(&info.key.type_id());
+        for index in self.by_type.values_mut().chain(self.by_name.values_mut()) {
+            if *index > i {
+                *index -= 1;
+            }
+        }
+    }
+
+    pub fn get(&self, type_id: TypeId) -> Option<&ComponentInfo> {
+        self.by_type.get(&type_id).map(|i| &self.infos[*i])
+    }
+
+    pub fn get_by_name(&self, name: &str) -> Option<&ComponentInfo> {
+        self.by_name.get(name).map(|i| &self.infos[*i])
+    }
+
+    /// Like [`ComponentRegistry::get`], but fails with an error naming the component.
+    pub fn info(&self, key: &ComponentKey) -> anyhow::Result<&ComponentInfo> {
+        self.get(key.type_id())
+            .ok_or_else(|| anyhow!("component {} is not registered", key.type_name()))
+    }
+
+    /// Like [`ComponentRegistry::get_by_name`], but fails with an error listing the known names.
+    pub fn info_by_name(&self, name: &str) -> anyhow::Result<&ComponentInfo> {
+        self.get_by_name(name).ok_or_else(|| {
+            let mut known: Vec<&str> = self.by_name.keys().map(String::as_str).collect();
+            known.sort();
+            anyhow!("unknown component {:?}, the registered ones are [{}]", name, known.join(", "))
+        })
+    }
+
+    /// Every registered component, in the order they were registered.
+    pub fn iter(&self) -> impl Iterator<Item = &ComponentInfo> {
+        self.infos.iter()
+    }
+
+    /// The components of an entity, with what is known about them.
+    pub fn components<'e>(&self, entity: &Entity<'e>) -> Vec<(&'e ComponentKey, Option<&ComponentInfo>)> {
+        entity.data().keys().iter()
+            .map(|key| (key, self.get(key.type_id())))
+            .collect()
+    }
+
+    /// One line per component of the entity, with its registered name and label, and its `Debug` output.
+    /// Components that are not registered are shown by type name and size.
+    pub fn describe(&self, entity: &Entity) -> Vec<String> {
+        self.components(entity).into_iter()
+            .map(|(key, info)| {
+                let data = entity.data();
+                let (name, value) = match info.and_then(|info| Some((info.name(), info.format(data, key)?))) {
+                    Some(described) => described,
+                    None => (key.type_name(), format!("<{} bytes, not registered>", data.get_bytes(key).unwrap().len())),
+                };
+                match key.label() {
+                    None => format!("{}: {}", name, value),
+                    Some(label) => format!("{}[{}]: {}", name, label, value),
+                }
+            })
+            .collect()
+    }
+
+    /// Replaces a component of an entity by the one read from JSON.
+    /// The entity must already have the component, and it must be registered with serde.
+    pub fn edit(&self, world: &mut World, id: EntityId, name: &str, label: Option<&str>, value: Value) -> anyhow::Result<()> {
+        let info = self.info_by_name(name)?;
+        let value = info.deserialize(value)?;
+        world.insert_value(id, &info.key().with_label(label), value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+
+    use crate::game::component::*;
+
+    #[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct Health(u32);
+
+    #[derive(Copy, Clone, Debug)]
+    #[allow(dead_code)]
+    struct Velocity {
+        x: f64,
+        y: f64,
+    }
+
+    #[derive(Copy, Clone)]
+    #[allow(dead_code)]
+    struct Hidden(u16);
+
+    #[test]
+    fn components_are_handled_by_name() {
+        let mut registry = ComponentRegistry::new();
+        registry.register_serde::<Health>("Health").register::<Velocity>("Velocity");
+        let mut world = World::new();
+        world.new_entity_mut()
+            .insert(Health(3))
+            .insert_labelled(Health(5), "max")
+            .insert(Velocity { x: 1., y: -0.5 })
+            .insert(Hidden(0));
+        world.flush();
+        let id = world.entities().next().unwrap().id();
+
+        let velocity = registry.get_by_name("Velocity").unwrap();
+        assert_eq!((velocity.size(), velocity.align(), velocity.has_serde()), (16, 8, false));
+        assert_eq!(registry.iter().map(|info| info.name()).collect::<Vec<_>>(), ["Health", "Velocity"]);
+
+        registry.edit(&mut world, id, "Health", Some("max"), json!(9)).unwrap();
+        let mut lines = registry.describe(&world.get(id).unwrap());
+        lines.sort();
+        assert_eq!(lines, [
+            "Health: Health(3)",
+            "Health[max]: Health(9)",
+            "Velocity: Velocity { x: 1.0, y: -0.5 }",
+            &format!("{}: <2 bytes, not registered>", type_name::<Hidden>()),
+        ]);
+
+        // values are only read as the type they were registered for
+        let entity = world.get(id).unwrap();
+        let health = registry.get_by_name("Health").unwrap();
+        assert_eq!(health.format(entity.data(), &ComponentKey::labelled::<Health>("max")).as_deref(), Some("Health(9)"));
+        assert_eq!(health.format(entity.data(), &ComponentKey::of::<Hidden>()), None);
+        assert_eq!(health.serialize(entity.data(), &ComponentKey::of::<Health>()).unwrap(), json!(3));
+        assert!(health.serialize(entity.data(), &ComponentKey::labelled::<Health>("min")).is_err());
+        assert!(velocity.serialize(entity.data(), &ComponentKey::of::<Velocity>()).is_err());
+
+        // no serde, unknown name, and a component the entity lacks
+        assert!(registry.edit(&mut world, id, "Velocity", None, json!({ "x": 0., "y": 0. })).is_err());
+        assert!(registry.edit(&mut world, id, "Mana", None, json!(1)).is_err());
+        assert!(registry.edit(&mut world, id, "Health", Some("min"), json!(1)).is_err());
+    }
+}
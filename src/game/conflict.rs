@@ -0,0 +1,203 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+
+use crate::game::component::ComponentKey;
+use crate::game::entity::{Change, Changes, EntityChange, EntityId};
+use crate::game::schedule::SystemRef;
+
+/// What happens when several systems write the same component of an entity in one tick.
+pub enum MergePolicy {
+    /// Only the write that comes last is applied. This is the default.
+    LastWriterWins,
+    /// Only the write that comes first is applied.
+    FirstWriterWins,
+    /// The writes are folded into one, in the order they were made.
+    /// If they cannot be combined, the conflict is logged as an error and only the last write is applied.
+    Combine(CombineFn),
+    /// None of the writes are applied, and the conflict is logged as an error.
+    Error,
+}
+
+/// Merges two writes to the same component. Returns None if they are not the expected type.
+pub type CombineFn =
+    Box<dyn Fn(&dyn EntityChange, &dyn EntityChange) -> Option<Box<dyn EntityChange>> + Send + Sync>;
+
+impl MergePolicy {
+    /// Merges the values written by two `Change<T>`s with `f`.
+    pub fn combine<T: Clone + Send + Sync + 'static>(f: impl Fn(&T, &T) -> T + Send + Sync + 'static) -> Self {
+        MergePolicy::Combine(Box::new(move |first, second| {
+            let (first, second): (&dyn Any, &dyn Any) = (first, second);
+            let first = first.downcast_ref::<Change<T>>()?;
+            let second = second.downcast_ref::<Change<T>>()?;
+            let data = f(first.data()?, second.data()?);
+            Some(match first.label() {
+                None => Change::new(data),
+                Some(label) => Change::labelled(data, label),
+            })
+        }))
+    }
+}
+
+/// A change, the entity it is for and the system that made it.
+pub(crate) type Write = (EntityId, SystemRef, Box<dyn EntityChange>);
+
+/// Splits [`Changes`] into the changes they are made of.
+fn flatten(entity: EntityId, system: SystemRef, change: Box<dyn EntityChange>, out: &mut Vec<Write>) {
+    let any: &dyn Any = &*change;
+    if !any.is::<Changes>() {
+        out.push((entity, system, change));
+        return;
+    }
+    let any: Box<dyn Any> = change;
+    for change in any.downcast::<Changes>().unwrap().into_vec() {
+        flatten(entity, system, change, out);
+    }
+}
+
+/// Merges the writes several systems made to the same component of the same entity,
+/// keeping every other change in the order it was made.
+/// `report` names the systems involved in a conflict, when conflicts should be logged.
+pub(crate) fn resolve(
+    changes: Vec<Write>,
+    policies: &HashMap<TypeId, MergePolicy>,
+    report: Option<&dyn Fn(SystemRef) -> String>,
+) -> Vec<(EntityId, Box<dyn EntityChange>)> {
+    let mut writes = Vec::with_capacity(changes.len());
+    for (entity, system, change) in changes {
+        flatten(entity, system, change, &mut writes);
+    }
+
+    let mut groups: HashMap<(EntityId, ComponentKey), Vec<usize>> = HashMap::new();
+    for (i, (entity, _, change)) in writes.iter().enumerate() {
+        if let Some(key) = change.key() {
+            groups.entry((*entity, key)).or_default().push(i);
+        }
+    }
+    let mut conflicts: Vec<_> = groups.into_iter().filter(|(_, group)| group.len() > 1).collect();
+    conflicts.sort_by_key(|(_, group)| group[0]);
+
+    let mut writes: Vec<_> = writes.into_iter().map(Some).collect();
+    for ((entity, key), group) in conflicts {
+        let policy = policies.get(&key.type_id()).unwrap_or(&MergePolicy::LastWriterWins);
+        let describe = |name_of: &dyn Fn(SystemRef) -> String| {
+            let names: Vec<String> = group.iter()
+                .map(|i| name_of(writes[*i].as_ref().unwrap().1))
+                .collect();
+            format!("{} writes to {} of entity {} from {}", group.len(), key, entity, names.join(", "))
+        };
+        if let Some(name_of) = report {
+            log::warn!("conflict: {}", describe(name_of));
+        }
+
+        let last = *group.last().unwrap();
+        match policy {
+            MergePolicy::LastWriterWins => {
+                for i in &group[..group.len() - 1] {
+                    writes[*i] = None;
+                }
+            }
+            MergePolicy::FirstWriterWins => {
+                for i in &group[1..] {
+                    writes[*i] = None;
+                }
+            }
+            MergePolicy::Combine(combine) => {
+                let change = |i: usize| &*writes[i].as_ref().unwrap().2;
+                let mut merged = combine(change(group[0]), change(group[1]));
+                for i in &group[2..] {
+                    merged = merged.and_then(|merged| combine(&*merged, change(*i)));
+                }
+                match merged {
+                    // the merged write takes the place of the last one
+                    Some(merged) => {
+                        let (entity, system, _) = writes[group[0]].take().unwrap();
+                        for i in &group[1..] {
+                            writes[*i] = None;
+                        }
+                        writes[last] = Some((entity, system, merged));
+                    }
+                    None => {
+                        let name_of = |system: SystemRef| format!("{:?}", system);
+                        log::error!("writes could not be combined, only the last one is kept: {}", describe(report.unwrap_or(&name_of)));
+                        for i in &group[..group.len() - 1] {
+                            writes[*i] = None;
+                        }
+                    }
+                }
+            }
+            MergePolicy::Error => {
+                let name_of = |system: SystemRef| format!("{:?}", system);
+                log::error!("conflicting writes were dropped: {}", describe(report.unwrap_or(&name_of)));
+                for i in &group {
+                    writes[*i] = None;
+                }
+            }
+        }
+    }
+
+    writes.into_iter().flatten().map(|(entity, _, change)| (entity, change)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::game::conflict::*;
+    use crate::game::entity::Attach;
+    use crate::game::query::Query;
+    use crate::game::GameState;
+    use crate::game::schedule::Times;
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    struct Pos(f32);
+
+    fn game_with_writers(policy: Option<MergePolicy>) -> (GameState, EntityId) {
+        let mut game = GameState::new();
+        let id = game.new_entity_mut().insert(Pos(0.)).id();
+        game.add_linear_system(Times::SimulationTick, |_, _| Some(Change::new(Pos(1.))));
+        game.add_query_system(Times::SimulationTick, Query::<&mut Pos>::new(|p| {
+            p.0 = 2.;
+            None
+        }));
+        game.add_linear_system(Times::SimulationTick, |_, _| Some(Changes::new(vec![Attach::new(0u8), Change::new(Pos(4.))])));
+        if let Some(policy) = policy {
+            game.set_merge_policy::<Pos>(policy);
+        }
+        game.report_conflicts = true;
+        game.sim_tick(Duration::ZERO);
+        (game, id)
+    }
+
+    fn pos_after(policy: Option<MergePolicy>) -> Option<Pos> {
+        let (game, id) = game_with_writers(policy);
+        // changes that don't conflict are always applied
+        assert_eq!(game.get(id).unwrap().get::<u8>(), Some(0));
+        game.get(id).unwrap().get::<Pos>()
+    }
+
+    #[test]
+    fn merge_policies() {
+        // linear systems run before query systems
+        assert_eq!(pos_after(None), Some(Pos(2.)));
+        assert_eq!(pos_after(Some(MergePolicy::LastWriterWins)), Some(Pos(2.)));
+        assert_eq!(pos_after(Some(MergePolicy::FirstWriterWins)), Some(Pos(1.)));
+        assert_eq!(pos_after(Some(MergePolicy::combine(|a: &Pos, b: &Pos| Pos(a.0 + b.0)))), Some(Pos(7.)));
+        // writes that cannot be combined fall back to the last one
+        assert_eq!(pos_after(Some(MergePolicy::Combine(Box::new(|_, _| None)))), Some(Pos(2.)));
+        assert_eq!(pos_after(Some(MergePolicy::Error)), Some(Pos(0.)));
+    }
+
+    #[test]
+    fn labelled_writes_do_not_conflict() {
+        let mut game = GameState::new();
+        let id = game.new_entity_mut().insert(Pos(0.)).insert_labelled(Pos(0.), "old").id();
+        game.set_merge_policy::<Pos>(MergePolicy::Error);
+        game.add_linear_system(Times::SimulationTick, |_, _| Some(Change::new(Pos(1.))));
+        game.add_linear_system(Times::SimulationTick, |_, _| Some(Change::labelled(Pos(2.), "old")));
+        game.sim_tick(Duration::ZERO);
+
+        let entity = game.get(id).unwrap();
+        assert_eq!(entity.get::<Pos>(), Some(Pos(1.)));
+        assert_eq!(entity.get_labelled::<Pos>("old"), Some(Pos(2.)));
+    }
+}
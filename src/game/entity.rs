@@ -1,77 +1,388 @@
+use std::any::Any;
 use std::fmt;
+use std::mem::MaybeUninit;
 
 use anyhow::anyhow;
 
+use crate::game::archetype::{read_component, Archetype, ComponentTicks};
+use crate::game::component::{ComponentKey, ComponentValue};
+use crate::game::world::World;
 use crate::util::arena::ComponentArena;
 
-pub struct Entity {
-    id: u64,
-    data: ComponentArena,  //TODO: components should be stored and looped over together
+/// Handle to an entity.
+/// The generation tells apart entities that reused the same index,
+/// so a handle to a despawned entity never resolves to its replacement.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct EntityId {
+    index: u32,
+    generation: u32,
 }
 
-impl Entity {
-    pub fn new(id: u64) -> Self {
+impl EntityId {
+    pub fn new(index: u32, generation: u32) -> Self {
+        EntityId { index, generation }
+    }
+
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+}
+
+impl fmt::Display for EntityId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}v{}", self.index, self.generation)
+    }
+}
+
+/// Read-only view of an entity stored in the [`World`].
+#[derive(Copy, Clone)]
+pub struct Entity<'a> {
+    id: EntityId,
+    data: EntityData<'a>,
+}
+
+/// The components of a single entity: one row of its archetype.
+#[derive(Copy, Clone)]
+pub struct EntityData<'a> {
+    archetype: &'a Archetype,
+    row: usize,
+}
+
+impl<'a> Entity<'a> {
+    pub(crate) fn new(id: EntityId, archetype: &'a Archetype, row: usize) -> Self {
         Entity {
             id,
-            data: ComponentArena::new(),
+            data: EntityData { archetype, row },
         }
     }
 
-    pub fn id(&self) -> u64 {
+    pub fn id(&self) -> EntityId {
         self.id
     }
 
-    pub fn data(&self) -> &ComponentArena {
+    pub fn data(&self) -> &EntityData<'a> {
         &self.data
     }
 
-    pub fn mut_data(&mut self) -> &mut ComponentArena {
-        &mut self.data
+    pub fn get<T: Clone + 'static>(&self) -> Option<T> {
+        self.data.get()
+    }
+
+    pub fn get_labelled<T: Clone + 'static>(&self, label: &str) -> Option<T> {
+        self.data.get_labelled(label)
     }
 
-    pub fn resolve_changes(&mut self, changes: Box<dyn EntityChange>) {
-        // todo error catching for resolve_changes
-        changes.arena_insert(self.mut_data()).unwrap();
+    pub fn has<T: 'static>(&self) -> bool {
+        self.data.has::<T>()
     }
 
-    pub fn print_comp<T: fmt::Display + Clone>(&self, label: &str) -> Option<()> {
-        let comp: T = self.data.get(label)?;
+    pub fn print_comp<T: fmt::Display + Clone + 'static>(&self) -> Option<()> {
+        let comp: T = self.get()?;
         print!("{}", comp);
         Some(())
     }
 }
 
+impl<'a> EntityData<'a> {
+    pub fn get<T: Clone + 'static>(&self) -> Option<T> {
+        read_component(self.get_bytes(&ComponentKey::of::<T>())?)
+    }
+
+    pub fn get_labelled<T: Clone + 'static>(&self, label: &str) -> Option<T> {
+        read_component(self.get_bytes(&ComponentKey::labelled::<T>(label))?)
+    }
+
+    /// The bytes of a component, to be read as its type.
+    pub fn get_bytes(&self, key: &ComponentKey) -> Option<&'a [MaybeUninit<u8>]> {
+        self.archetype.get_bytes(self.row, key)
+    }
+
+    pub fn has<T: 'static>(&self) -> bool {
+        self.archetype.has(&ComponentKey::of::<T>())
+    }
+
+    pub fn has_labelled<T: 'static>(&self, label: &str) -> bool {
+        self.archetype.has(&ComponentKey::labelled::<T>(label))
+    }
+
+    /// When the component was added and last changed.
+    pub fn ticks(&self, key: &ComponentKey) -> Option<ComponentTicks> {
+        self.archetype.get_ticks(self.row, key)
+    }
+
+    /// Whether the entity got its `T` after the given change tick.
+    pub fn added_since<T: 'static>(&self, tick: u64) -> bool {
+        self.ticks(&ComponentKey::of::<T>()).is_some_and(|ticks| ticks.is_added(tick))
+    }
+
+    /// Whether the entity's `T` was added or written to after the given change tick.
+    pub fn changed_since<T: 'static>(&self, tick: u64) -> bool {
+        self.ticks(&ComponentKey::of::<T>()).is_some_and(|ticks| ticks.is_changed(tick))
+    }
+
+    pub fn keys(&self) -> &'a [ComponentKey] {
+        self.archetype.key()
+    }
+
+    pub fn archetype(&self) -> &'a Archetype {
+        self.archetype
+    }
+}
+
+/// Mutable access to an entity stored in the [`World`].
+/// Structural edits move the entity between archetypes right away.
+pub struct EntityMut<'a> {
+    id: EntityId,
+    world: &'a mut World,
+}
+
+impl<'a> EntityMut<'a> {
+    pub(crate) fn new(id: EntityId, world: &'a mut World) -> Self {
+        EntityMut { id, world }
+    }
+
+    pub fn id(&self) -> EntityId {
+        self.id
+    }
+
+    pub fn entity(&self) -> Entity<'_> {
+        self.world.get(self.id).unwrap()
+    }
+
+    pub fn insert<T: Clone + Send + Sync + 'static>(&mut self, data: T) -> anyhow::Result<()> {
+        self.world.insert(self.id, data, None)
+    }
+
+    pub fn attach<T: Clone + Send + Sync + 'static>(&mut self, data: T) -> anyhow::Result<()> {
+        self.world.attach(self.id, data, None)
+    }
+
+    pub fn detach<T: 'static>(&mut self) -> anyhow::Result<()> {
+        self.world.detach(self.id, &ComponentKey::of::<T>())
+    }
+
+    pub fn despawn(self) -> anyhow::Result<()> {
+        self.world.despawn(self.id)
+    }
+}
+
+/// An entity that is still being put together.
+/// Its components are kept in a [`ComponentArena`] until the [`World`] stores it.
+#[derive(Clone)]
+pub struct EntityBuilder {
+    id: EntityId,
+    data: ComponentArena<ComponentKey>,
+}
+
+impl EntityBuilder {
+    pub fn new(id: EntityId) -> Self {
+        EntityBuilder {
+            id,
+            data: ComponentArena::default(),
+        }
+    }
+
+    pub fn id(&self) -> EntityId {
+        self.id
+    }
+
+    pub fn data(&self) -> &ComponentArena<ComponentKey> {
+        &self.data
+    }
+
+    pub fn mut_data(&mut self) -> &mut ComponentArena<ComponentKey> {
+        &mut self.data
+    }
+
+    pub fn insert<T: Clone + Send + Sync + 'static>(&mut self, data: T) -> &mut Self {
+        self.data.alloc(data, ComponentKey::of::<T>());
+        self
+    }
+
+    pub fn insert_labelled<T: Clone + Send + Sync + 'static>(&mut self, data: T, label: &str) -> &mut Self {
+        self.data.alloc(data, ComponentKey::labelled::<T>(label));
+        self
+    }
+
+    /// The components of the entity, leaving out the ones stored as another type than their key says.
+    pub(crate) fn into_components(self) -> Vec<(ComponentKey, ComponentValue)> {
+        let id = self.id;
+        self.data.into_values().into_iter()
+            .filter(|(key, value)| {
+                let matches = value.ty().type_id() == key.type_id();
+                if !matches {
+                    log::error!("entity {} has a {:?} stored as {}, which is left out", id, value.ty(), key);
+                }
+                matches
+            })
+            .collect()
+    }
+}
+
 
 /// What changes are done to an Entity?
-pub trait EntityChange {
-    fn arena_insert(self: Box<Self>, arena: &mut ComponentArena) -> anyhow::Result<()>;
+/// Changes are created on worker threads and applied on the main one, so they must be `Send`.
+pub trait EntityChange: Any + Send {
+    fn apply(self: Box<Self>, world: &mut World, entity: EntityId) -> anyhow::Result<()>;
+
+    /// The component this change overwrites, if any.
+    /// Writes to the same component in one tick are merged by its [`MergePolicy`](crate::game::conflict::MergePolicy).
+    fn key(&self) -> Option<ComponentKey> {
+        None
+    }
 }
 
 /// Does a single change to the Entity
-pub struct Change<T: Clone> {
-    label: String,
+pub struct Change<T: Clone + Send + Sync + 'static> {
+    label: Option<String>,
     data: Option<T>,
 }
 
-impl<T: Clone> EntityChange for Change<T> {
-    fn arena_insert(self: Box<Self>, arena: &mut ComponentArena) -> anyhow::Result<()> {
+impl<T: Clone + Send + Sync + 'static> EntityChange for Change<T> {
+    fn apply(self: Box<Self>, world: &mut World, entity: EntityId) -> anyhow::Result<()> {
         if self.data.is_none() {
             return Err(anyhow!("Change has no data!"));
         }
         let (label, data) = (self.label, self.data.unwrap());
-        arena.insert::<T>(data, &label)
+        world.insert::<T>(entity, data, label.as_deref())
+    }
+
+    fn key(&self) -> Option<ComponentKey> {
+        Some(ComponentKey::new::<T>(self.label.as_deref()))
     }
 }
 
-impl<T: Clone> Change<T> {
-    pub fn new(change: T, label: &str) -> Box<Self> {
+impl<T: Clone + Send + Sync + 'static> Change<T> {
+    pub fn new(change: T) -> Box<Self> {
         Box::new(Self {
-            label: String::from(label),
+            label: None,
             data: Some(change),
         })
     }
+
+    pub fn labelled(change: T, label: &str) -> Box<Self> {
+        Box::new(Self {
+            label: Some(String::from(label)),
+            data: Some(change),
+        })
+    }
+
+    pub fn data(&self) -> Option<&T> {
+        self.data.as_ref()
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+}
+
+/// Several changes done to the Entity, applied in order
+pub struct Changes {
+    changes: Vec<Box<dyn EntityChange>>,
+}
+
+impl EntityChange for Changes {
+    fn apply(self: Box<Self>, world: &mut World, entity: EntityId) -> anyhow::Result<()> {
+        for change in self.changes {
+            change.apply(world, entity)?;
+        }
+        Ok(())
+    }
+}
+
+impl Changes {
+    pub fn new(changes: Vec<Box<dyn EntityChange>>) -> Box<Self> {
+        Box::new(Self { changes })
+    }
+
+    pub fn into_vec(self) -> Vec<Box<dyn EntityChange>> {
+        self.changes
+    }
+}
+
+/// Creates a new entity, built by the given function once the change is applied
+pub struct Spawn {
+    build: Box<dyn FnOnce(&mut EntityBuilder) + Send>,
+}
+
+impl EntityChange for Spawn {
+    fn apply(self: Box<Self>, world: &mut World, _entity: EntityId) -> anyhow::Result<()> {
+        (self.build)(world.new_entity_mut());
+        Ok(())
+    }
+}
+
+impl Spawn {
+    pub fn new(build: impl FnOnce(&mut EntityBuilder) + Send + 'static) -> Box<Self> {
+        Box::new(Self { build: Box::new(build) })
+    }
+}
+
+/// Removes the Entity from the world
+pub struct Despawn;
+
+impl EntityChange for Despawn {
+    fn apply(self: Box<Self>, world: &mut World, entity: EntityId) -> anyhow::Result<()> {
+        world.despawn(entity)
+    }
+}
+
+impl Despawn {
+    pub fn new() -> Box<Self> {
+        Box::new(Self)
+    }
+}
+
+/// Adds a new component to the Entity, or replaces the one with the same key
+pub struct Attach<T: Clone + Send + Sync + 'static> {
+    label: Option<String>,
+    data: T,
+}
+
+impl<T: Clone + Send + Sync + 'static> EntityChange for Attach<T> {
+    fn apply(self: Box<Self>, world: &mut World, entity: EntityId) -> anyhow::Result<()> {
+        world.attach::<T>(entity, self.data, self.label.as_deref())
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> Attach<T> {
+    pub fn new(data: T) -> Box<Self> {
+        Box::new(Self { label: None, data })
+    }
+
+    pub fn labelled(data: T, label: &str) -> Box<Self> {
+        Box::new(Self {
+            label: Some(String::from(label)),
+            data,
+        })
+    }
+}
+
+/// Removes a component from the Entity
+pub struct Detach {
+    key: ComponentKey,
+}
+
+impl EntityChange for Detach {
+    fn apply(self: Box<Self>, world: &mut World, entity: EntityId) -> anyhow::Result<()> {
+        world.detach(entity, &self.key)
+    }
+}
+
+impl Detach {
+    pub fn new<T: 'static>() -> Box<Self> {
+        Box::new(Self { key: ComponentKey::of::<T>() })
+    }
+
+    pub fn labelled<T: 'static>(label: &str) -> Box<Self> {
+        Box::new(Self { key: ComponentKey::labelled::<T>(label) })
+    }
 }
 
 pub trait Component {
-    fn to_entity(self, entity: &mut Entity);
+    fn to_entity(self, entity: &mut EntityBuilder);
 }
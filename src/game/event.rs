@@ -0,0 +1,145 @@
+use std::any::type_name;
+
+use anyhow::anyhow;
+
+use crate::game::entity::{EntityChange, EntityId};
+use crate::game::resource::Resources;
+use crate::game::world::World;
+
+/// Typed events sent between systems, kept as a resource.
+///
+/// Events are sent with [`SendEvent`], which goes through the change pipeline,
+/// so they can be read by the systems that run after the sender in the same tick,
+/// and by every system in the next tick. They are cleared after that.
+#[derive(Clone, Debug)]
+pub struct Events<E> {
+    previous: Vec<E>,
+    current: Vec<E>,
+}
+
+impl<E> Default for Events<E> {
+    fn default() -> Self {
+        Events {
+            previous: Vec::new(),
+            current: Vec::new(),
+        }
+    }
+}
+
+impl<E: Clone + Send + Sync + 'static> Events<E> {
+    pub fn send(&mut self, event: E) {
+        self.current.push(event);
+    }
+
+    /// Events sent this tick so far.
+    pub fn current(&self) -> &[E] {
+        &self.current
+    }
+
+    /// Events sent during the last tick.
+    pub fn previous(&self) -> &[E] {
+        &self.previous
+    }
+
+    /// Every event that is still around, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &E> {
+        self.previous.iter().chain(self.current.iter())
+    }
+
+    pub fn len(&self) -> usize {
+        self.previous.len() + self.current.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Starts a new tick: the events of the last one are dropped,
+    /// and the ones of this tick become the last ones.
+    pub fn update(&mut self) {
+        self.previous = std::mem::take(&mut self.current);
+    }
+
+    /// Calls [`Events::update`] on the `Events<E>` resource.
+    pub(crate) fn update_resource(resources: &mut Resources) {
+        if let Some(events) = resources.get_mut::<Events<E>>() {
+            events.update();
+        }
+    }
+}
+
+/// Sends an event, whichever entity the change is returned for.
+/// The event type must have been added with `GameState::add_event`.
+pub struct SendEvent<E: Clone + Send + Sync + 'static> {
+    event: E,
+}
+
+impl<E: Clone + Send + Sync + 'static> EntityChange for SendEvent<E> {
+    fn apply(self: Box<Self>, world: &mut World, _entity: EntityId) -> anyhow::Result<()> {
+        let events = world.resources_mut().get_mut::<Events<E>>()
+            .ok_or_else(|| anyhow!("events of type {} were never added", type_name::<E>()))?;
+        events.send(self.event);
+        Ok(())
+    }
+}
+
+impl<E: Clone + Send + Sync + 'static> SendEvent<E> {
+    pub fn new(event: E) -> Box<Self> {
+        Box::new(Self { event })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::game::entity::{Change, EntityChange, EntityId};
+    use crate::game::event::*;
+    use crate::game::schedule::{Stage, Times};
+    use crate::game::GameState;
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    struct Damage {
+        target: EntityId,
+        amount: u32,
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    struct Health(u32);
+
+    #[derive(Copy, Clone)]
+    struct Attacker(EntityId);
+
+    #[test]
+    fn events_are_seen_by_later_systems_and_the_next_tick() {
+        let mut game = GameState::new();
+        game.add_event::<Damage>();
+        let target = game.new_entity_mut().insert(Health(10)).id();
+        game.new_entity_mut().insert(Attacker(target));
+
+        game.add_linear_system(Times::EveryTicks(10), |entity, _| {
+            let Attacker(target) = entity.get()?;
+            Some(SendEvent::new(Damage { target, amount: 3 }))
+        });
+        // sees the events in the same tick
+        game.add_linear_system(Times::SimulationTick, |entity, resources| {
+            let health = entity.get::<Health>()?;
+            let damage: u32 = resources.get::<Events<Damage>>()?.current().iter()
+                .filter(|d| d.target == entity.id())
+                .map(|d| d.amount)
+                .sum();
+            Some(Change::new(Health(health.0 - damage)) as Box<dyn EntityChange>)
+        }).in_stage(Stage::PostUpdate);
+
+        let events = |game: &GameState| game.resource::<Events<Damage>>().unwrap().len();
+        game.sim_tick(Duration::ZERO);
+        assert_eq!(game.get(target).unwrap().get::<Health>(), Some(Health(7)));
+        assert_eq!(events(&game), 1);
+        // still there during the next tick, but only as a previous event
+        game.sim_tick(Duration::ZERO);
+        assert_eq!(game.get(target).unwrap().get::<Health>(), Some(Health(7)));
+        assert_eq!(events(&game), 1);
+        game.sim_tick(Duration::ZERO);
+        assert_eq!(events(&game), 0);
+    }
+}
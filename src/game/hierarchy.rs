@@ -0,0 +1,234 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::game::component::ComponentKey;
+use crate::game::entity::EntityId;
+use crate::game::transform::{get_pos, Transform2D, Transform3D};
+use crate::game::world::World;
+use crate::util::Either;
+
+/// Makes an entity the child of another one. Its transform is then relative to the parent's,
+/// and it is despawned along with its parent.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Parent(pub EntityId);
+
+/// Where a child with a [`Transform2D`] is in the world, set by [`propagate_transforms`].
+/// Entities without a parent don't get one, as their transform already is their world transform.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GlobalTransform2D(pub Transform2D);
+
+/// Where a child with a [`Transform3D`] is in the world, set by [`propagate_transforms`].
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GlobalTransform3D(pub Transform3D);
+
+/// The entities whose parent is the given one. The world keeps them indexed by parent.
+pub fn children(world: &World, parent: EntityId) -> Vec<EntityId> {
+    world.children(parent).to_vec()
+}
+
+/// Computes the world transform of every child from its own transform and the ones of its ancestors.
+/// A child whose parent is gone, or has another kind of transform, is placed as if it had no parent.
+/// The game state runs this after every tick.
+pub fn propagate_transforms(world: &mut World) {
+    let mut parents = HashMap::new();
+    let mut orphans = Vec::new();
+    for entity in world.entities() {
+        match entity.get::<Parent>() {
+            Some(Parent(parent)) => {
+                parents.insert(entity.id(), parent);
+            }
+            // the parent was detached since the last propagation
+            None if entity.has::<GlobalTransform2D>() || entity.has::<GlobalTransform3D>() => orphans.push(entity.id()),
+            None => {}
+        }
+    }
+
+    let mut globals = HashMap::new();
+    let mut visiting = HashSet::new();
+    for child in parents.keys() {
+        global(world, &parents, *child, &mut globals, &mut visiting);
+    }
+
+    for child in parents.keys() {
+        let (t_2d, t_3d) = match globals[child] {
+            Some(Either::This(t)) => (Some(GlobalTransform2D(t)), None),
+            Some(Either::That(t)) => (None, Some(GlobalTransform3D(t))),
+            None => (None, None),
+        };
+        set(world, *child, t_2d);
+        set(world, *child, t_3d);
+    }
+    for id in orphans {
+        set::<GlobalTransform2D>(world, id, None);
+        set::<GlobalTransform3D>(world, id, None);
+    }
+}
+
+type WorldTransform = Option<Either<Transform2D, Transform3D>>;
+
+/// The world transform of an entity, computed once per propagation.
+fn global(
+    world: &World,
+    parents: &HashMap<EntityId, EntityId>,
+    id: EntityId,
+    globals: &mut HashMap<EntityId, WorldTransform>,
+    visiting: &mut HashSet<EntityId>,
+) -> WorldTransform {
+    if let Some(done) = globals.get(&id) {
+        return *done;
+    }
+    let local = world.get(id).and_then(|entity| get_pos(entity.data()));
+    let parent = parents.get(&id).copied().filter(|parent| world.contains(*parent));
+    let result = match parent {
+        // an entity still being visited is one of its own ancestors
+        Some(_) if !visiting.insert(id) => {
+            log::warn!("entity {} is its own ancestor", id);
+            local
+        }
+        Some(parent) => match (global(world, parents, parent, globals, visiting), local) {
+            (Some(Either::This(parent)), Some(Either::This(child))) => Some(Either::This(parent.compose(&child))),
+            (Some(Either::That(parent)), Some(Either::That(child))) => Some(Either::That(parent.compose(&child))),
+            _ => local,
+        },
+        None => local,
+    };
+    globals.insert(id, result);
+    result
+}
+
+/// Sets or removes a component, attaching it if the entity doesn't have it yet.
+/// Values that didn't move are left alone, so they aren't marked as changed.
+fn set<T: Clone + PartialEq + Send + Sync + 'static>(world: &mut World, id: EntityId, value: Option<T>) {
+    let old = world.get(id).and_then(|entity| entity.get::<T>());
+    let result = match (value, old) {
+        (Some(value), Some(old)) if old == value => Ok(()),
+        (Some(value), Some(_)) => world.insert(id, value, None),
+        (Some(value), None) => world.attach(id, value, None),
+        (None, Some(_)) => world.detach(id, &ComponentKey::of::<T>()),
+        (None, None) => Ok(()),
+    };
+    if let Err(err) = result {
+        log::error!("failed to set the world transform of entity {}: {}", id, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use cgmath::{Deg, Quaternion, Rotation3, Vector3};
+
+    use crate::game::entity::{Change, Despawn};
+    use crate::game::hierarchy::*;
+    use crate::game::schedule::Times;
+    use crate::game::transform::get_world_pos;
+    use crate::game::GameState;
+
+    fn at(x: f32, y: f32) -> Transform2D {
+        Transform2D { pos: [x, y], size: [1., 1.], rot: 0. }
+    }
+
+    fn close(a: [f32; 2], b: [f32; 2]) -> bool {
+        (a[0] - b[0]).abs() < 1e-5 && (a[1] - b[1]).abs() < 1e-5
+    }
+
+    #[test]
+    fn children_follow_their_parents() {
+        let mut game = GameState::new();
+        let character = game.new_entity_mut().insert(Transform2D { rot: 0.5, ..at(10., 0.) }).id();
+        let hand = game.new_entity_mut().insert(at(2., 0.)).insert(Parent(character)).id();
+        let weapon = game.new_entity_mut().insert(at(0., 1.)).insert(Parent(hand)).id();
+        game.add_linear_system(Times::SimulationTick, |entity, _| {
+            if entity.has::<Parent>() {
+                return None;
+            }
+            let t = entity.get::<Transform2D>()?;
+            Some(Change::new(Transform2D { pos: [t.pos[0] + 1., t.pos[1]], ..t }))
+        });
+
+        game.sim_tick(Duration::ZERO);
+        let world_pos = |game: &GameState, id| match get_world_pos(game.get(id).unwrap().data()) {
+            Some(Either::This(t)) => t,
+            _ => panic!("no 2D transform"),
+        };
+        // the character is turned by half a turn of PI, so the hand sticks up
+        assert!(close(world_pos(&game, character).pos, [11., 0.]));
+        assert!(close(world_pos(&game, hand).pos, [11., 2.]));
+        assert!(close(world_pos(&game, weapon).pos, [10., 2.]));
+        assert_eq!(world_pos(&game, weapon).rot, 0.5);
+        // the local transforms are left alone
+        assert!(close(game.get(weapon).unwrap().get::<Transform2D>().unwrap().pos, [0., 1.]));
+
+        // once it is dropped, the weapon is where its own transform says, and moves on its own
+        game.world.detach(weapon, &ComponentKey::of::<Parent>()).unwrap();
+        game.sim_tick(Duration::ZERO);
+        assert!(close(world_pos(&game, weapon).pos, [1., 1.]));
+        assert!(!game.get(weapon).unwrap().has::<GlobalTransform2D>());
+    }
+
+    #[test]
+    fn children_are_rotated_in_3d() {
+        let mut world = World::new();
+        let parent = world.new_entity_mut().insert(Transform3D {
+            pos: [0., 0., 5.],
+            size: [2., 2., 2.],
+            rotation: Quaternion::from_angle_z(Deg(90.)),
+        }).id();
+        let child = world.new_entity_mut().insert(Transform3D {
+            pos: [1., 0., 0.],
+            size: [1., 1., 1.],
+            rotation: Quaternion::from_angle_z(Deg(0.)),
+        }).insert(Parent(parent)).id();
+        world.flush();
+        propagate_transforms(&mut world);
+
+        let GlobalTransform3D(t) = world.get(child).unwrap().get().unwrap();
+        let offset = Vector3::from(t.pos) - Vector3::new(0., 2., 5.);
+        assert!(offset.x.abs() < 1e-5 && offset.y.abs() < 1e-5 && offset.z.abs() < 1e-5);
+        assert_eq!(t.size, [2., 2., 2.]);
+    }
+
+    #[test]
+    fn children_are_indexed_by_parent() {
+        let mut world = World::new();
+        let a = world.new_entity_mut().id();
+        let b = world.new_entity_mut().id();
+        let child = world.new_entity_mut().insert(Parent(a)).id();
+        world.flush();
+        assert_eq!(children(&world, a), vec![child]);
+
+        // moved to another parent, then moved between archetypes
+        world.insert(child, Parent(b), None).unwrap();
+        world.attach(child, 1u32, None).unwrap();
+        assert!(children(&world, a).is_empty());
+        assert_eq!(children(&world, b), vec![child]);
+
+        world.detach(child, &ComponentKey::of::<Parent>()).unwrap();
+        assert!(children(&world, b).is_empty());
+        world.attach(child, Parent(a), None).unwrap();
+        world.despawn(child).unwrap();
+        assert!(children(&world, a).is_empty());
+    }
+
+    #[test]
+    fn despawning_a_parent_despawns_its_children() {
+        let mut game = GameState::new();
+        let parent = game.new_entity_mut().insert(at(0., 0.)).id();
+        let child = game.new_entity_mut().insert(at(1., 0.)).insert(Parent(parent)).id();
+        let grandchild = game.new_entity_mut().insert(Parent(child)).id();
+        let other = game.new_entity_mut().insert(at(2., 0.)).id();
+        game.world.flush();
+        assert_eq!(children(&game.world, parent), vec![child]);
+
+        game.add_linear_system(Times::SimulationTick, move |entity, _| {
+            if entity.id() != parent {
+                return None;
+            }
+            Some(Despawn::new())
+        });
+        game.sim_tick(Duration::ZERO);
+        for id in [parent, child, grandchild] {
+            assert!(!game.world.contains(id));
+        }
+        assert!(game.world.contains(other));
+    }
+}
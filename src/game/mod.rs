@@ -1,94 +1,891 @@
+use std::any::{type_name, TypeId};
+use std::collections::HashMap;
 use std::fmt;
-use std::time::Duration;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
 
-use crate::game::entity::{Entity, EntityChange};
+use anyhow::{anyhow, Context};
+use rayon::prelude::*;
 
+use crate::game::broadphase::{position, PairChange, PairSystem, SpatialHash};
+use crate::game::component::{ComponentKey, ComponentRegistry, ComponentValue};
+use crate::game::conflict::{resolve, MergePolicy, Write};
+use crate::game::entity::{Entity, EntityBuilder, EntityChange, EntityId, EntityMut};
+use crate::game::event::Events;
+use crate::game::hierarchy::propagate_transforms;
+use crate::game::query::QuerySystem;
+use crate::game::resource::Resources;
+use crate::game::scene::Scene;
+use crate::game::schedule::{plan, Run, Scheduled, SystemConfig, SystemRef, Times};
+use crate::game::snapshot::Snapshot;
+use crate::game::system::System;
+use crate::game::time::{FixedTimestep, Time};
+use crate::game::transform::{get_world_pos, lerp_pos, Transform2D, Transform3D};
+use crate::util::Either;
+use crate::game::world::World;
+use crate::util::arena::ComponentArena;
+
+pub mod archetype;
+pub mod broadphase;
+pub mod component;
+pub mod conflict;
 pub mod entity;
+pub mod event;
+pub mod hierarchy;
+pub mod query;
+pub mod resource;
+pub mod scene;
+pub mod schedule;
+pub mod snapshot;
+pub mod system;
+pub mod time;
 pub mod transform;
+pub mod world;
 
-type LinearSystem = fn(&Entity) -> Option<Box<dyn EntityChange>>;
-type QuadraticSystem = fn(&Entity, &Entity) -> Option<Box<dyn EntityChange>>;
+pub type LinearSystem = Box<dyn System>;
+pub type QuadraticSystem = Box<dyn Fn(&Entity, &Entity, &Resources) -> Option<PairChange> + Send + Sync>;
+/// Systems with exclusive access to the world, run one at a time before the others.
+/// Used for things like spawning the starting entities.
+pub type WorldSystem = Box<dyn FnMut(&mut World) + Send + Sync>;
 
 pub struct GameState {
-    pub entities: Vec<Entity>,
-    pub linear_systems: Vec<LinearSystem>,
-    pub quadratic_systems: Vec<QuadraticSystem>,
-    next_id: u64
+    pub world: World,
+    pub world_systems: Vec<Scheduled<WorldSystem>>,
+    pub linear_systems: Vec<Scheduled<LinearSystem>>,
+    pub quadratic_systems: Vec<Scheduled<PairSystem>>,
+    pub query_systems: Vec<Scheduled<Box<dyn QuerySystem>>>,
+    /// How writes to the same component in one tick are merged, by component type
+    pub merge_policies: HashMap<TypeId, MergePolicy>,
+    /// Logs every conflicting write, along with the systems that made it
+    pub report_conflicts: bool,
+    /// The components that can be saved to and loaded from scenes
+    pub components: ComponentRegistry,
+    /// How long a tick is, and how many may be run to catch up after a slow frame
+    pub timestep: FixedTimestep,
+    started: bool,
+    // the batches the systems run in, planned again when a system is added
+    plan: Option<Vec<Vec<SystemRef>>>,
+    // ticks run so far
+    ticks: u64,
+    // swap the buffers of every event type at the start of a tick
+    event_updates: Vec<fn(&mut Resources)>,
+    // world transforms before the last tick, to interpolate from
+    previous_transforms: HashMap<EntityId, Either<Transform2D, Transform3D>>,
 }
 
+// Components can only be stored if they are `Send + Sync`, which makes the type-erased storage
+// thread-safe too: the game state can be moved to a simulation thread, and read from several at once.
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<GameState>();
+    assert_send_sync::<World>();
+    assert_send_sync::<Entity<'static>>();
+    assert_send_sync::<EntityBuilder>();
+    assert_send_sync::<ComponentArena<ComponentKey>>();
+    assert_send_sync::<ComponentValue>();
+    assert_send_sync::<Snapshot>();
+};
 
-/// When and how many times should a System be run?
-pub enum Times {
-    Startup,
-    SimulationTick,
+/// Every entity of the tick, with what the quadratic systems need to find their pairs.
+struct Pairs<'p, 'w> {
+    /// every entity, along with the index of its archetype
+    entities: &'p [(usize, Entity<'w>)],
+    /// empty unless quadratic systems are being run
+    positions: Vec<Option<[f32; 3]>>,
+    /// the quadratic systems being run, with their spatial hash unless they visit every pair
+    hashes: Vec<(usize, Option<SpatialHash>)>,
 }
 
+impl Default for GameState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl GameState {
     pub fn new() -> Self {
+        let mut world = World::new();
+        world.resources_mut().insert(Time::default());
+        let mut components = ComponentRegistry::new();
+        components.register_serde::<Transform2D>("Transform2D");
         GameState {
-            entities: Vec::new(),
+            world,
+            // systems that get the whole world to themselves
+            world_systems: Vec::new(),
             // systems that are applied on single entities
             linear_systems: Vec::new(),
             // systems that are applied on pairs of entities
             quadratic_systems: Vec::new(),
-            next_id: 0,
+            // systems that are applied on the entities matching a query
+            query_systems: Vec::new(),
+            merge_policies: HashMap::new(),
+            report_conflicts: false,
+            components,
+            timestep: FixedTimestep::default(),
+            started: false,
+            plan: None,
+            ticks: 0,
+            event_updates: Vec::new(),
+            previous_transforms: HashMap::new(),
         }
     }
 
-    pub fn sim_tick(&mut self, _delta_t: Duration) {
-        let mut changes: Vec<(usize, Box<dyn EntityChange>)> = Vec::new();
+    // the added system is returned, so it can be renamed and ordered
+    pub fn add_world_system<F>(&mut self, times: Times, system: F) -> &mut Scheduled<WorldSystem>
+    where
+        F: FnMut(&mut World) + Send + Sync + 'static,
+    {
+        self.plan = None;
+        self.world_systems.push(Scheduled::new(times, type_name::<F>(), Box::new(system)));
+        self.world_systems.last_mut().unwrap()
+    }
 
-        for (i, entity) in self.entities.iter().enumerate() {
-            // first we apply every linear system to it
-            for lin_sys in self.linear_systems.iter() {
-                if let Some(change) = lin_sys(entity) {
-                    changes.push((i, change));
-                }
+    pub fn add_linear_system<F>(&mut self, times: Times, system: F) -> &mut Scheduled<LinearSystem>
+    where
+        F: Fn(&Entity, &Resources) -> Option<Box<dyn EntityChange>> + Send + Sync + 'static,
+    {
+        self.add_system(times, system)
+    }
+
+    /// Adds a linear system that keeps its own state.
+    pub fn add_system(&mut self, times: Times, system: impl System + 'static) -> &mut Scheduled<LinearSystem> {
+        let name = system.name().to_string();
+        self.plan = None;
+        self.linear_systems.push(Scheduled::new(times, name, Box::new(system)));
+        self.linear_systems.last_mut().unwrap()
+    }
+
+    pub fn add_quadratic_system(&mut self, times: Times, system: impl Into<PairSystem>)
+                                -> &mut Scheduled<PairSystem> {
+        let system = system.into();
+        let name = system.name.clone();
+        self.plan = None;
+        self.quadratic_systems.push(Scheduled::new(times, name, system));
+        self.quadratic_systems.last_mut().unwrap()
+    }
+
+    pub fn add_query_system(&mut self, times: Times, system: Box<dyn QuerySystem>)
+                            -> &mut Scheduled<Box<dyn QuerySystem>> {
+        let name = system.name().to_string();
+        self.plan = None;
+        self.query_systems.push(Scheduled::new(times, name, system));
+        self.query_systems.last_mut().unwrap()
+    }
+
+    /// Sets how writes to a `T` made by several systems in the same tick are merged.
+    pub fn set_merge_policy<T: 'static>(&mut self, policy: MergePolicy) {
+        self.merge_policies.insert(TypeId::of::<T>(), policy);
+    }
+
+    /// Lets systems send and read events of type `E`, through the `Events<E>` resource.
+    pub fn add_event<E: Clone + Send + Sync + 'static>(&mut self) {
+        if self.resource::<Events<E>>().is_none() {
+            self.insert_resource(Events::<E>::default());
+            self.event_updates.push(Events::<E>::update_resource);
+        }
+    }
+
+    /// Sends an event from outside the systems, such as input handling.
+    pub fn send_event<E: Clone + Send + Sync + 'static>(&mut self, event: E) -> anyhow::Result<()> {
+        self.resource_mut::<Events<E>>()
+            .ok_or_else(|| anyhow!("events of type {} were never added", type_name::<E>()))?
+            .send(event);
+        Ok(())
+    }
+
+    /// Time of the last simulation tick.
+    pub fn time(&self) -> Time {
+        self.resource::<Time>().copied().unwrap_or_default()
+    }
+
+    /// How fast the simulation runs compared to real time. 0 pauses it.
+    pub fn set_time_scale(&mut self, scale: f32) {
+        let time = Time { scale, ..self.time() };
+        self.insert_resource(time);
+    }
+
+    fn config(&self, system: SystemRef) -> &SystemConfig {
+        match system {
+            SystemRef::World(i) => &self.world_systems[i].config,
+            SystemRef::Linear(i) => &self.linear_systems[i].config,
+            SystemRef::Query(i) => &self.query_systems[i].config,
+            SystemRef::Quadratic(i) => &self.quadratic_systems[i].config,
+        }
+    }
+
+    fn system_name(&self, system: SystemRef) -> String {
+        self.config(system).name.clone()
+    }
+
+    /// The order the systems run in: batches of systems that run together,
+    /// each one seeing the changes made by the batches before it.
+    /// Fails if the systems can't be ordered, for example because of a cycle.
+    pub fn schedule(&self) -> anyhow::Result<Vec<Vec<SystemRef>>> {
+        let world = self.world_systems.iter().enumerate().map(|(i, s)| (SystemRef::World(i), &s.config));
+        let linear = self.linear_systems.iter().enumerate().map(|(i, s)| (SystemRef::Linear(i), &s.config));
+        let query = self.query_systems.iter().enumerate().map(|(i, s)| (SystemRef::Query(i), &s.config));
+        let quadratic = self.quadratic_systems.iter().enumerate()
+            .map(|(i, s)| (SystemRef::Quadratic(i), &s.config));
+        let systems: Vec<_> = world.chain(linear).chain(query).chain(quadratic).collect();
+        plan(&systems)
+    }
+
+    /// Adds a resource, or replaces the one of the same type.
+    pub fn insert_resource<T: Send + Sync + 'static>(&mut self, value: T) {
+        self.world.resources_mut().insert(value);
+    }
+
+    pub fn resource<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.world.resources().get()
+    }
+
+    pub fn resource_mut<T: Clone + Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+        self.world.resources_mut().get_mut()
+    }
+
+    /// Copies every entity and resource, so the game can be rolled back to this point.
+    /// Every component is cloned, so this costs as much as the world is big.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(self.world.clone(), self.ticks)
+    }
+
+    /// Rolls every entity and resource back to the snapshot. Systems are kept as they are.
+    /// Entities are drawn where the snapshot left them, without interpolating from before the rollback.
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        // change ticks never go back, as systems remember the last one they ran at
+        let change_tick = self.world.change_tick();
+        self.world = snapshot.world().clone();
+        self.world.catch_up_change_tick(change_tick);
+        self.ticks = snapshot.ticks();
+        self.previous_transforms.clear();
+        self.timestep.reset();
+    }
+
+    /// Writes every entity to a JSON scene file. Their components must all be registered in `components`.
+    pub fn save_scene(&mut self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        self.world.flush();
+        let json = Scene::save(&self.world, &self.components)?.to_json()?;
+        fs::write(path.as_ref(), json)
+            .with_context(|| format!("cannot write scene {}", path.as_ref().display()))
+    }
+
+    /// Spawns the entities of a JSON scene file, and returns their ids.
+    pub fn load_scene(&mut self, path: impl AsRef<Path>) -> anyhow::Result<Vec<EntityId>> {
+        let json = fs::read_to_string(path.as_ref())
+            .with_context(|| format!("cannot read scene {}", path.as_ref().display()))?;
+        Scene::from_json(&json)?.load(&mut self.world, &self.components)
+    }
+
+    /// Runs the startup systems. Does nothing if they already ran.
+    /// Called by the first tick or frame, if not before.
+    /// Fails if the systems can't be ordered, in which case none of them run until one is added.
+    pub fn startup(&mut self) -> anyhow::Result<()> {
+        if self.started {
+            return Ok(());
+        }
+        self.started = true;
+        self.plan()?;
+        self.run(Run::Startup);
+        Ok(())
+    }
+
+    /// Plans the order of the systems, unless they were not changed since the last time.
+    /// Systems changed through the public lists rather than the `add_*` methods are not noticed.
+    fn plan(&mut self) -> anyhow::Result<()> {
+        if self.plan.is_some() {
+            return Ok(());
+        }
+        match self.schedule() {
+            Ok(plan) => {
+                self.plan = Some(plan);
+                Ok(())
+            }
+            Err(err) => {
+                // nothing runs, and the error is only reported once
+                self.plan = Some(Vec::new());
+                Err(err)
             }
+        }
+    }
 
-            // then we loop through every other entity
-            for other in self.entities.iter() {
-                if entity.id() != other.id() {
-                    // apply every quadratic system on this pair
-                    for quad_sys in self.quadratic_systems.iter() {
-                        if let Some(change) = quad_sys(entity, other) {
-                            // changes are only applied to the first entity
-                            changes.push((i, change));
-                        }
-                    }
-                }
+    /// Runs as many ticks as are due after `real_delta` of real time, per the [`FixedTimestep`].
+    /// Returns how many were run.
+    /// The world transforms before the last tick are kept, to interpolate from.
+    pub fn advance(&mut self, real_delta: Duration) -> u32 {
+        let ticks = self.timestep.ticks_for(real_delta);
+        for n in 0..ticks {
+            if n == ticks - 1 {
+                self.remember_transforms();
+            }
+            self.sim_tick(self.timestep.tick());
+        }
+        ticks
+    }
+
+    fn remember_transforms(&mut self) {
+        self.previous_transforms.clear();
+        self.previous_transforms.extend(self.world.entities()
+            .filter_map(|entity| Some((entity.id(), get_world_pos(entity.data())?))));
+    }
+
+    pub fn sim_tick(&mut self, delta_t: Duration) {
+        if let Err(err) = self.startup() {
+            log::error!("systems were not run: {}", err);
+        }
+        let time = self.time().advance(self.ticks, delta_t);
+        self.insert_resource(time);
+        for update in self.event_updates.iter() {
+            update(self.world.resources_mut());
+        }
+        self.run(Run::Tick {
+            index: time.tick,
+            elapsed: time.elapsed,
+            delta: time.delta,
+        });
+        propagate_transforms(&mut self.world);
+        self.ticks += 1;
+    }
+
+    /// Where an entity should be drawn: between its world transform before the last tick and now,
+    /// as far as the time carried over to the next tick goes.
+    /// Only ticks run by [`GameState::advance`] are interpolated.
+    pub fn interpolated_pos(&self, entity: &Entity) -> Option<Either<Transform2D, Transform3D>> {
+        let current = get_world_pos(entity.data())?;
+        Some(match self.previous_transforms.get(&entity.id()) {
+            Some(previous) => lerp_pos(*previous, current, self.timestep.alpha()),
+            None => current,
+        })
+    }
+
+    /// Runs the systems that should be run every rendered frame.
+    pub fn frame(&mut self) {
+        if let Err(err) = self.startup() {
+            log::error!("systems were not run: {}", err);
+        }
+        self.run(Run::Frame);
+    }
+
+    fn run(&mut self, run: Run) {
+        // entities created since the last tick join their archetypes
+        self.world.flush();
+
+        if let Err(err) = self.plan() {
+            log::error!("systems were not run: {}", err);
+        }
+        // systems can't be added while they run, so the plan stays the same
+        let plan = self.plan.take().unwrap();
+        for batch in plan.iter() {
+            let batch: Vec<SystemRef> = batch.iter().copied()
+                .filter(|system| self.config(*system).times.due(run))
+                .collect();
+            if !batch.is_empty() {
+                self.run_batch(&batch);
+            }
+        }
+        self.plan = Some(plan);
+    }
+
+    fn run_batch(&mut self, batch: &[SystemRef]) {
+        // world systems can't run alongside anything else
+        for system in batch {
+            if let SystemRef::World(i) = system {
+                let start = Instant::now();
+                (self.world_systems[*i].system)(&mut self.world);
+                log::trace!("{} took {:?}", self.world_systems[*i].config.name, start.elapsed());
+            }
+        }
+        self.world.flush();
+
+        let (mut linear, mut query, mut quadratic) = (Vec::new(), Vec::new(), Vec::new());
+        for system in batch {
+            match *system {
+                SystemRef::World(_) => {}
+                SystemRef::Linear(i) => linear.push(i),
+                SystemRef::Query(i) => query.push(i),
+                SystemRef::Quadratic(i) => quadratic.push(i),
             }
         }
+        if linear.is_empty() && query.is_empty() && quadratic.is_empty() {
+            return;
+        }
+        let start = Instant::now();
+        for i in linear.iter() {
+            self.linear_systems[*i].system.prepare(&self.world);
+        }
+        for i in query.iter() {
+            self.query_systems[*i].system.prepare(&self.world);
+        }
+        // what the systems write is newer than what they read, their own writes included
+        self.world.increment_change_tick();
+
+        // query systems are skipped for whole archetypes at once
+        let queries: Vec<Vec<usize>> = self.world.archetypes().iter()
+            .map(|archetype| query.iter().copied()
+                .filter(|q| self.query_systems[*q].system.matches(archetype))
+                .collect())
+            .collect();
+        let entities: Vec<(usize, Entity)> = self.world.archetypes().iter().enumerate()
+            .flat_map(|(i, archetype)| archetype.iter().map(move |entity| (i, entity)))
+            .collect();
+
+        // quadratic systems with bounds only look at the pairs their spatial hash finds
+        let positions: Vec<Option<[f32; 3]>> = if quadratic.is_empty() {
+            Vec::new()
+        } else {
+            entities.iter()
+                .map(|(_, entity)| position(entity.data()))
+                .collect()
+        };
+        let pairs = Pairs {
+            entities: &entities,
+            hashes: quadratic.into_iter()
+                .map(|i| (i, SpatialHash::new(self.quadratic_systems[i].system.bounds, &positions)))
+                .collect(),
+            positions,
+        };
+
+        // the systems only read the world, so entities are spread over the thread pool.
+        // collect keeps the results in entity order, which keeps the tick deterministic
+        let changes: Vec<Vec<Write>> = entities.par_iter().enumerate()
+            .map(|(n, (i, entity))| self.run_systems(n, entity, &linear, &queries[*i], &pairs))
+            .collect();
+
+        // writes to the same component are merged before anything is applied
+        let changes = changes.into_iter().flatten().collect();
+        let name_of = |system| self.system_name(system);
+        let report = self.report_conflicts.then_some(&name_of as &dyn Fn(SystemRef) -> String);
+        let changes = resolve(changes, &self.merge_policies, report);
+        if log::log_enabled!(log::Level::Trace) {
+            let names: Vec<String> = batch.iter().filter(|s| !matches!(s, SystemRef::World(_)))
+                .map(|s| self.system_name(*s))
+                .collect();
+            log::trace!("{} took {:?}", names.join(", "), start.elapsed());
+        }
 
         // now we apply the changes
-        for (i, change) in changes {
-            if let Some(entity) = self.entities.get_mut(i) {
-                entity.resolve_changes(change);
+        for (id, change) in changes {
+            if let Err(err) = self.world.resolve_changes(id, change) {
+                log::error!("failed to apply change to entity {}: {}", id, err);
+            }
+        }
+        // entities spawned by the changes
+        self.world.flush();
+    }
+
+    /// Runs every system on one entity, returning the changes in the order they were made,
+    /// along with the system that made them.
+    fn run_systems(&self, n: usize, entity: &Entity, linear: &[usize], queries: &[usize], pairs: &Pairs)
+                   -> Vec<Write> {
+        let mut changes = Vec::new();
+        let id = entity.id();
+        let resources = self.world.resources();
+
+        // first we apply every linear system to it
+        for i in linear.iter() {
+            changes.extend(self.linear_systems[*i].system.run(entity, resources).map(|c| (id, SystemRef::Linear(*i), c)));
+        }
+
+        // then every query system that matches it
+        for i in queries.iter() {
+            changes.extend(self.query_systems[*i].system.run(entity, resources).map(|c| (id, SystemRef::Query(*i), c)));
+        }
+
+        // then we loop through every other entity
+        for (i, hash) in pairs.hashes.iter() {
+            let pair_sys = &self.quadratic_systems[*i].system;
+            // unordered pairs are only visited from their first entity
+            let first = if pair_sys.unordered { n + 1 } else { 0 };
+            let candidates = match (hash, pairs.positions[n]) {
+                (None, _) => Either::That(first..pairs.entities.len()),
+                (Some(hash), Some(pos)) => Either::This(hash.candidates(pos, &pairs.positions).into_iter()),
+                // bounded systems skip entities without a transform
+                (Some(_), None) => Either::This(Vec::new().into_iter()),
+            };
+            for other in candidates.filter(|other| *other >= first && *other != n) {
+                let (_, other) = &pairs.entities[other];
+                let system = SystemRef::Quadratic(*i);
+                match (pair_sys.system)(entity, other, resources) {
+                    None => {}
+                    Some(PairChange::This(c)) => changes.push((id, system, c)),
+                    Some(PairChange::Other(c)) => changes.push((other.id(), system, c)),
+                    Some(PairChange::Both(this, that)) => {
+                        changes.push((id, system, this));
+                        changes.push((other.id(), system, that));
+                    }
+                }
             }
         }
+        changes
+    }
+
+    /// Iterates over every entity that has been stored in the world.
+    pub fn entities(&self) -> impl Iterator<Item = Entity<'_>> {
+        self.world.entities()
+    }
+
+    /// Looks up an entity. Returns None if it was despawned.
+    pub fn get(&self, id: EntityId) -> Option<Entity<'_>> {
+        self.world.get(id)
+    }
+
+    pub fn get_mut(&mut self, id: EntityId) -> Option<EntityMut<'_>> {
+        self.world.get_mut(id)
     }
 
     #[allow(dead_code)]
-    pub fn new_entity(&mut self) -> &Entity {
-        self.entities.push(Entity::new(self.next_id));
-        self.next_id += 1;
-        self.entities.last().unwrap()
+    pub fn new_entity(&mut self) -> &EntityBuilder {
+        self.world.new_entity_mut()
     }
 
     #[allow(dead_code)]
-    pub fn new_entity_mut(&mut self) -> &mut Entity {
-        self.entities.push(Entity::new(self.next_id));
-        self.next_id += 1;
-        self.entities.last_mut().unwrap()
+    pub fn new_entity_mut(&mut self) -> &mut EntityBuilder {
+        self.world.new_entity_mut()
     }
 
-    pub fn print_comps<T: fmt::Display + Clone>(&self, comp_label: &str) {
-        println!("Components {}:", comp_label);
-        for entity in self.entities.iter() {
+    pub fn print_comps<T: fmt::Display + Clone + 'static>(&self) {
+        println!("Components {}:", std::any::type_name::<T>());
+        for entity in self.entities() {
             print!("{}: ", entity.id());
-            let _ = entity.print_comp::<T>(comp_label);
+            let _ = entity.print_comp::<T>();
             println!();
         }
     }
+
+    /// Prints every component of an entity that is registered in `components`, and the size of the others.
+    pub fn print_entity(&self, id: EntityId) {
+        match self.get(id) {
+            None => println!("Entity {} does not exist", id),
+            Some(entity) => {
+                println!("Entity {}:", id);
+                for line in self.components.describe(&entity) {
+                    println!("  {}", line);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::game::broadphase::{PairChange, PairSystem};
+    use crate::game::conflict::MergePolicy;
+    use crate::game::entity::{Attach, Change, Entity, EntityChange, EntityId, Spawn};
+    use crate::game::transform::Transform2D;
+    use crate::game::query::{Query, Resource};
+    use crate::game::resource::{Resources, SetResource, UpdateResource};
+    use crate::game::schedule::{Stage, Times};
+    use crate::game::time::{FixedTimestep, Time};
+    use crate::game::GameState;
+    use crate::util::Either;
+
+    #[test]
+    fn parallel_changes_apply_in_entity_order() {
+        let mut game = GameState::new();
+        let count = 2000u32;
+        for i in 0..count {
+            game.new_entity_mut().insert(i);
+        }
+        game.add_linear_system(Times::SimulationTick, |entity, _| {
+            let i = entity.get::<u32>()?;
+            if i >= 2000 {
+                return None;
+            }
+            Some(Spawn::new(move |e| { e.insert(i + 2000); }))
+        });
+        game.sim_tick(Duration::ZERO);
+
+        // spawned entities get their ids in the order their changes were applied
+        let mut spawned: Vec<_> = game.entities()
+            .filter_map(|e| Some((e.id(), e.get::<u32>()?)))
+            .filter(|(_, i)| *i >= 2000)
+            .collect();
+        spawned.sort();
+        assert_eq!(spawned.len(), count as usize);
+        for (n, (id, i)) in spawned.into_iter().enumerate() {
+            assert_eq!(id.index(), count + n as u32);
+            assert_eq!(i, count + n as u32);
+        }
+    }
+
+    fn close_pairs(quad_sys: PairSystem) -> Vec<(EntityId, EntityId)> {
+        let mut game = GameState::new();
+        for i in 0..300 {
+            let f = i as f32;
+            let pos = [(f * 0.37).sin() * 4., (f * 0.73).cos() * 4.];
+            game.new_entity_mut().insert(Transform2D { pos, size: [1., 1.], rot: 0. });
+        }
+        // entities without a transform are never close to anything
+        game.new_entity_mut().insert(0u32);
+        game.add_quadratic_system(Times::SimulationTick, quad_sys);
+        game.sim_tick(Duration::ZERO);
+
+        let mut pairs: Vec<_> = game.entities()
+            .filter_map(|e| e.get::<(EntityId, EntityId)>())
+            .collect();
+        pairs.sort();
+        pairs
+    }
+
+    #[test]
+    fn broadphase_matches_brute_force() {
+        fn record_close(entity: &Entity, other: &Entity, _: &Resources) -> Option<PairChange> {
+            let dist = Transform2D::dist(entity.get()?, other.get()?);
+            let pair = (entity.id(), other.id());
+            (dist <= 0.5).then(|| PairChange::This(Spawn::new(move |e| { e.insert(pair); })))
+        }
+
+        let brute_force = close_pairs(PairSystem::new(record_close));
+        assert!(!brute_force.is_empty());
+        assert_eq!(close_pairs(PairSystem::new(record_close).with_radius(0.5)), brute_force);
+        assert_eq!(close_pairs(PairSystem::new(record_close).with_aabb([0.5, 0.5, 0.])), brute_force);
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    struct Hits(u32);
+
+    fn hits_after(quad_sys: PairSystem) -> Vec<u32> {
+        let mut game = GameState::new();
+        for _ in 0..4 {
+            game.new_entity_mut().insert(Hits(0));
+        }
+        game.set_merge_policy::<Hits>(MergePolicy::combine(|a: &Hits, b: &Hits| Hits(a.0 + b.0)));
+        game.add_quadratic_system(Times::SimulationTick, quad_sys);
+        game.sim_tick(Duration::ZERO);
+        game.entities().map(|e| e.get::<Hits>().unwrap().0).collect()
+    }
+
+    #[test]
+    fn pair_changes_reach_either_entity() {
+        fn both(_: &Entity, _: &Entity, _: &Resources) -> Option<PairChange> {
+            Some(PairChange::Both(Change::new(Hits(1)), Change::new(Hits(1))))
+        }
+        fn other(_: &Entity, _: &Entity, _: &Resources) -> Option<PairChange> {
+            Some(PairChange::Other(Change::new(Hits(1))))
+        }
+
+        // every entity is in 3 pairs, visited once per order
+        assert_eq!(hits_after(PairSystem::new(both)), vec![6; 4]);
+        assert_eq!(hits_after(PairSystem::new(both).unordered()), vec![3; 4]);
+        assert_eq!(hits_after(PairSystem::new(other)), vec![3; 4]);
+        // unordered pairs are only visited from the entity that comes first
+        assert_eq!(hits_after(PairSystem::new(other).unordered()), vec![0, 1, 2, 3]);
+    }
+
+    fn bump(entity: &Entity, label: &str) -> Option<Box<dyn EntityChange>> {
+        Some(Change::labelled(entity.get_labelled::<u32>(label)? + 1, label))
+    }
+
+    #[test]
+    fn systems_run_on_their_schedule() {
+        let mut game = GameState::new();
+        game.add_world_system(Times::Startup, |world| {
+            let e = world.new_entity_mut();
+            for label in ["startup", "tick", "every 2 ticks", "every 0.5s", "frame"] {
+                e.insert_labelled(0u32, label);
+            }
+        });
+        game.add_linear_system(Times::Startup, |e, _| bump(e, "startup"));
+        game.add_linear_system(Times::SimulationTick, |e, _| bump(e, "tick"));
+        game.add_linear_system(Times::EveryTicks(2), |e, _| bump(e, "every 2 ticks"));
+        game.add_linear_system(Times::EverySeconds(0.5), |e, _| bump(e, "every 0.5s"));
+        game.add_linear_system(Times::Frame, |e, _| bump(e, "frame"));
+
+        for _ in 0..5 {
+            game.sim_tick(Duration::from_secs_f32(0.25));
+        }
+        game.frame();
+        game.frame();
+        game.startup().unwrap();
+
+        let entity = game.entities().next().unwrap();
+        let count = |label| entity.get_labelled::<u32>(label).unwrap();
+        assert_eq!(count("startup"), 1);
+        assert_eq!(count("tick"), 5);
+        assert_eq!(count("every 2 ticks"), 3);
+        assert_eq!(count("every 0.5s"), 2);
+        assert_eq!(count("frame"), 2);
+        assert_eq!((game.time().tick, game.time().elapsed), (4, Duration::from_secs_f32(1.25)));
+    }
+
+    fn ordered_game(double_after_increment: bool) -> (GameState, EntityId) {
+        let mut game = GameState::new();
+        let id = game.new_entity_mut().insert(1u32).id();
+        game.add_linear_system(Times::SimulationTick, |e, _| Some(Change::new(e.get::<u32>()? * 2)))
+            .named("double");
+        let increment = game.add_linear_system(Times::SimulationTick, |e, _| Some(Change::new(e.get::<u32>()? + 1)))
+            .named("increment");
+        if double_after_increment {
+            increment.before("double");
+        }
+        // runs after both, whatever their order
+        game.add_linear_system(Times::SimulationTick, |e, _| Some(Attach::labelled(e.get::<u32>()?, "seen")))
+            .in_stage(Stage::PostUpdate);
+        (game, id)
+    }
+
+    #[test]
+    fn ordered_systems_see_earlier_changes() {
+        let (mut game, id) = ordered_game(false);
+        game.sim_tick(Duration::ZERO);
+        // both systems read 1, and the last write wins
+        assert_eq!(game.get(id).unwrap().get::<u32>(), Some(2));
+        assert_eq!(game.get(id).unwrap().get_labelled::<u32>("seen"), Some(2));
+
+        let (mut game, id) = ordered_game(true);
+        game.sim_tick(Duration::ZERO);
+        assert_eq!(game.get(id).unwrap().get::<u32>(), Some(4));
+        assert_eq!(game.get(id).unwrap().get_labelled::<u32>("seen"), Some(4));
+    }
+
+    #[test]
+    fn cycles_stop_the_systems() {
+        let (mut game, id) = ordered_game(true);
+        game.linear_systems[0].after("increment").before("increment");
+        assert!(game.schedule().is_err());
+        assert!(game.startup().is_err());
+        // the cycle is only reported once
+        assert!(game.startup().is_ok());
+        game.sim_tick(Duration::ZERO);
+        assert_eq!(game.get(id).unwrap().get::<u32>(), Some(1));
+
+        // adding a system plans them again
+        game.add_linear_system(Times::SimulationTick, |_, _| None);
+        assert!(game.plan().is_err());
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Score(u32);
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Step(u32);
+
+    #[test]
+    fn systems_read_and_change_resources() {
+        let mut game = GameState::new();
+        game.insert_resource(Score(0));
+        game.insert_resource(Step(10));
+        let a = game.new_entity_mut().insert(1u32).id();
+        let b = game.new_entity_mut().insert(2u32).id();
+
+        game.add_linear_system(Times::SimulationTick, |entity, _| {
+            let points = entity.get::<u32>()?;
+            Some(UpdateResource::new(move |score: &mut Score| score.0 += points))
+        });
+        game.add_query_system(Times::SimulationTick, Query::<(&mut u32, Resource<Step>)>::new(|(n, step)| {
+            *n += step.0;
+            None
+        }));
+        game.add_linear_system(Times::SimulationTick, |_, resources| {
+            let step = resources.get::<Step>()?;
+            Some(SetResource::new(Step(step.0 + 1)))
+        }).in_stage(Stage::PostUpdate);
+
+        game.sim_tick(Duration::ZERO);
+        game.sim_tick(Duration::ZERO);
+
+        // updates from every entity add up
+        assert_eq!(game.resource::<Score>(), Some(&Score(1 + 2 + 11 + 12)));
+        assert_eq!(game.resource::<Step>(), Some(&Step(12)));
+        assert_eq!(game.get(a).unwrap().get::<u32>(), Some(1 + 10 + 11));
+        assert_eq!(game.get(b).unwrap().get::<u32>(), Some(2 + 10 + 11));
+    }
+
+    #[test]
+    fn systems_get_the_scaled_time() {
+        let mut game = GameState::new();
+        let id = game.new_entity_mut().insert(0f32).insert(0u64).id();
+        game.add_query_system(Times::SimulationTick, Query::<(&mut f32, &mut u64, Resource<Time>)>::new(|(x, tick, time)| {
+            *x += 2. * time.delta_secs();
+            *tick = time.tick;
+            None
+        }));
+
+        game.sim_tick(Duration::from_millis(500));
+        game.set_time_scale(0.5);
+        game.sim_tick(Duration::from_millis(500));
+        game.set_time_scale(0.);
+        game.sim_tick(Duration::from_millis(500));
+
+        let entity = game.get(id).unwrap();
+        assert_eq!(entity.get::<f32>(), Some(1.5));
+        assert_eq!(entity.get::<u64>(), Some(2));
+        assert_eq!(game.time().elapsed, Duration::from_millis(750));
+    }
+
+    #[test]
+    fn slow_frames_catch_up_and_are_interpolated() {
+        let mut game = GameState::new();
+        game.timestep = FixedTimestep::new(Duration::from_millis(10));
+        let id = game.new_entity_mut().insert(Transform2D { pos: [0., 0.], size: [1., 1.], rot: 0. }).id();
+        game.add_query_system(Times::SimulationTick, Query::<&mut Transform2D>::new(|t| {
+            t.pos[0] += 1.;
+            None
+        }));
+
+        assert_eq!(game.advance(Duration::from_millis(4)), 0);
+        assert_eq!(game.advance(Duration::from_millis(21)), 2);
+        assert_eq!(game.time().tick, 1);
+        assert_eq!(game.time().elapsed, Duration::from_millis(20));
+
+        // halfway between the last two ticks
+        let entity = game.get(id).unwrap();
+        assert_eq!(entity.get::<Transform2D>().unwrap().pos, [2., 0.]);
+        match game.interpolated_pos(&entity) {
+            Some(Either::This(t)) => assert!((t.pos[0] - 1.5).abs() < 1e-5),
+            _ => panic!("no 2D transform"),
+        }
+    }
+
+    #[test]
+    fn restoring_stops_the_interpolation() {
+        let mut game = GameState::new();
+        game.timestep = FixedTimestep::new(Duration::from_millis(10));
+        let id = game.new_entity_mut().insert(Transform2D { pos: [0., 0.], size: [1., 1.], rot: 0. }).id();
+        game.add_query_system(Times::SimulationTick, Query::<&mut Transform2D>::new(|t| {
+            t.pos[0] += 1.;
+            None
+        }));
+        game.advance(Duration::from_millis(10));
+        let snapshot = game.snapshot();
+        game.advance(Duration::from_millis(25));
+
+        game.restore(&snapshot);
+        assert_eq!(game.timestep.alpha(), 0.);
+        match game.interpolated_pos(&game.get(id).unwrap()) {
+            Some(Either::This(t)) => assert_eq!(t.pos, [1., 0.]),
+            _ => panic!("no 2D transform"),
+        }
+    }
+
+    #[test]
+    fn the_game_state_can_move_between_threads() {
+        let mut game = GameState::new();
+        for i in 0..4u32 {
+            game.new_entity_mut().insert(i).insert(Arc::new(i));
+        }
+        game.add_linear_system(Times::SimulationTick, |entity, _| {
+            Some(Change::new(entity.get::<u32>()? + 1))
+        });
+
+        // simulated on another thread
+        let mut game = std::thread::spawn(move || {
+            game.sim_tick(Duration::ZERO);
+            game
+        }).join().unwrap();
+        game.sim_tick(Duration::ZERO);
+
+        // and read from several at once
+        let game = &game;
+        let sums: Vec<u32> = std::thread::scope(|scope| {
+            let readers: Vec<_> = (0..2).map(|_| scope.spawn(move || {
+                game.entities().map(|e| e.get::<u32>().unwrap() + *e.get::<Arc<u32>>().unwrap()).sum()
+            })).collect();
+            readers.into_iter().map(|reader| reader.join().unwrap()).collect()
+        });
+        assert_eq!(sums, vec![(0..4).map(|i| i * 2 + 2).sum::<u32>(); 2]);
+    }
 }
@@ -0,0 +1,494 @@
+use std::any::type_name;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use crate::game::archetype::Archetype;
+use crate::game::component::ComponentKey;
+use crate::game::entity::{Change, Changes, Entity, EntityChange, EntityId};
+use crate::game::resource::Resources;
+use crate::game::world::World;
+
+/// Components a query system reads (`&T`) or writes (`&mut T`).
+///
+/// Components are copied out of the entity before the system runs.
+/// Once it returns, every `&mut T` that no longer equals the stored value is turned into a [`Change`],
+/// so writes go through the same pipeline as every other change.
+pub trait QueryData {
+    /// Owned copy of the components, alive while the system runs.
+    type State;
+    /// What the system is given.
+    type Item<'s>;
+
+    /// Adds the components an entity must have to match.
+    fn keys(keys: &mut Vec<ComponentKey>);
+
+    fn fetch(entity: &Entity, resources: &Resources) -> Option<Self::State>;
+
+    fn item(state: &mut Self::State) -> Self::Item<'_>;
+
+    /// Turns the components the system wrote to into changes.
+    fn write_back(state: Self::State, entity: &Entity, changes: &mut Vec<Box<dyn EntityChange>>);
+}
+
+impl<T: Clone + 'static> QueryData for &T {
+    type State = T;
+    type Item<'s> = &'s T;
+
+    fn keys(keys: &mut Vec<ComponentKey>) {
+        keys.push(ComponentKey::of::<T>());
+    }
+
+    fn fetch(entity: &Entity, _resources: &Resources) -> Option<T> {
+        entity.get::<T>()
+    }
+
+    fn item(state: &mut T) -> &T {
+        state
+    }
+
+    fn write_back(_state: T, _entity: &Entity, _changes: &mut Vec<Box<dyn EntityChange>>) {}
+}
+
+impl<T: Clone + PartialEq + Send + Sync + 'static> QueryData for &mut T {
+    type State = T;
+    type Item<'s> = &'s mut T;
+
+    fn keys(keys: &mut Vec<ComponentKey>) {
+        keys.push(ComponentKey::of::<T>());
+    }
+
+    fn fetch(entity: &Entity, _resources: &Resources) -> Option<T> {
+        entity.get::<T>()
+    }
+
+    fn item(state: &mut T) -> &mut T {
+        state
+    }
+
+    fn write_back(state: T, entity: &Entity, changes: &mut Vec<Box<dyn EntityChange>>) {
+        // only components that were actually modified are written, and marked as changed
+        if entity.get::<T>().as_ref() != Some(&state) {
+            changes.push(Change::new(state));
+        }
+    }
+}
+
+impl QueryData for EntityId {
+    type State = EntityId;
+    type Item<'s> = EntityId;
+
+    fn keys(_keys: &mut Vec<ComponentKey>) {}
+
+    fn fetch(entity: &Entity, _resources: &Resources) -> Option<EntityId> {
+        Some(entity.id())
+    }
+
+    fn item(state: &mut EntityId) -> EntityId {
+        *state
+    }
+
+    fn write_back(_state: EntityId, _entity: &Entity, _changes: &mut Vec<Box<dyn EntityChange>>) {}
+}
+
+/// Reads the `T` resource. Entities are skipped while there is none.
+pub struct Resource<T>(PhantomData<T>);
+
+impl<T: Send + Sync + 'static> QueryData for Resource<T> {
+    type State = Arc<T>;
+    type Item<'s> = &'s T;
+
+    fn keys(_keys: &mut Vec<ComponentKey>) {}
+
+    fn fetch(_entity: &Entity, resources: &Resources) -> Option<Arc<T>> {
+        resources.get_arc()
+    }
+
+    fn item(state: &mut Arc<T>) -> &T {
+        state
+    }
+
+    fn write_back(_state: Arc<T>, _entity: &Entity, _changes: &mut Vec<Box<dyn EntityChange>>) {}
+}
+
+macro_rules! tuple_query_data {
+    ($($name:ident),*) => {
+        #[allow(non_snake_case)]
+        impl<$($name: QueryData),*> QueryData for ($($name,)*) {
+            type State = ($($name::State,)*);
+            type Item<'s> = ($($name::Item<'s>,)*);
+
+            fn keys(keys: &mut Vec<ComponentKey>) {
+                $($name::keys(keys);)*
+            }
+
+            fn fetch(entity: &Entity, resources: &Resources) -> Option<Self::State> {
+                Some(($($name::fetch(entity, resources)?,)*))
+            }
+
+            fn item(state: &mut Self::State) -> Self::Item<'_> {
+                let ($($name,)*) = state;
+                ($($name::item($name),)*)
+            }
+
+            fn write_back(state: Self::State, entity: &Entity, changes: &mut Vec<Box<dyn EntityChange>>) {
+                let ($($name,)*) = state;
+                $($name::write_back($name, entity, changes);)*
+            }
+        }
+    };
+}
+
+tuple_query_data!(A);
+tuple_query_data!(A, B);
+tuple_query_data!(A, B, C);
+tuple_query_data!(A, B, C, D);
+tuple_query_data!(A, B, C, D, E);
+tuple_query_data!(A, B, C, D, E, F);
+
+/// Narrows down which archetypes a query system runs on,
+/// without giving the system access to the components.
+pub trait QueryFilter {
+    fn matches(archetype: &Archetype) -> bool;
+
+    /// Narrows it down further to single entities, given the change tick the system last ran at.
+    fn matches_entity(_entity: &Entity, _last_run: u64) -> bool {
+        true
+    }
+}
+
+/// Only entities that have a `T`.
+pub struct With<T>(PhantomData<T>);
+
+/// Only entities that don't have a `T`.
+pub struct Without<T>(PhantomData<T>);
+
+impl<T: 'static> QueryFilter for With<T> {
+    fn matches(archetype: &Archetype) -> bool {
+        archetype.has(&ComponentKey::of::<T>())
+    }
+}
+
+impl<T: 'static> QueryFilter for Without<T> {
+    fn matches(archetype: &Archetype) -> bool {
+        !archetype.has(&ComponentKey::of::<T>())
+    }
+}
+
+/// Only entities whose `T` was added or written to since the system last ran.
+pub struct Changed<T>(PhantomData<T>);
+
+/// Only entities that got their `T` since the system last ran.
+pub struct Added<T>(PhantomData<T>);
+
+impl<T: 'static> QueryFilter for Changed<T> {
+    fn matches(archetype: &Archetype) -> bool {
+        archetype.has(&ComponentKey::of::<T>())
+    }
+
+    fn matches_entity(entity: &Entity, last_run: u64) -> bool {
+        entity.data().changed_since::<T>(last_run)
+    }
+}
+
+impl<T: 'static> QueryFilter for Added<T> {
+    fn matches(archetype: &Archetype) -> bool {
+        archetype.has(&ComponentKey::of::<T>())
+    }
+
+    fn matches_entity(entity: &Entity, last_run: u64) -> bool {
+        entity.data().added_since::<T>(last_run)
+    }
+}
+
+impl QueryFilter for () {
+    fn matches(_archetype: &Archetype) -> bool {
+        true
+    }
+}
+
+macro_rules! tuple_query_filter {
+    ($($name:ident),*) => {
+        impl<$($name: QueryFilter),*> QueryFilter for ($($name,)*) {
+            fn matches(archetype: &Archetype) -> bool {
+                $($name::matches(archetype))&&*
+            }
+
+            fn matches_entity(entity: &Entity, last_run: u64) -> bool {
+                $($name::matches_entity(entity, last_run))&&*
+            }
+        }
+    };
+}
+
+tuple_query_filter!(A);
+tuple_query_filter!(A, B);
+tuple_query_filter!(A, B, C);
+tuple_query_filter!(A, B, C, D);
+
+/// A system that runs only on the entities matching a query.
+/// It may be run on several entities at once from different threads.
+pub trait QuerySystem: Send + Sync {
+    /// Whether the system should run on the entities of this archetype.
+    fn matches(&self, archetype: &Archetype) -> bool;
+
+    /// Used in logs, and by default as the name other systems are ordered around.
+    fn name(&self) -> &str {
+        type_name::<Self>()
+    }
+
+    /// Called before the system is run on the entities, with exclusive access to its state.
+    fn prepare(&mut self, _world: &World) {}
+
+    fn run(&self, entity: &Entity, resources: &Resources) -> Option<Box<dyn EntityChange>>;
+}
+
+type QueryFn<D> = Box<dyn for<'s> Fn(<D as QueryData>::Item<'s>) -> Option<Box<dyn EntityChange>> + Send + Sync>;
+
+/// System declaring the components it reads and writes, and which entities it cares about.
+/// With [`Changed`] or [`Added`] filters, it only sees the entities written to since it last ran,
+/// including by its own writes from that run.
+/// ```ignore
+/// Query::<(&Transform2D, &mut Velocity), With<Tag>>::new(|(t, v)| {
+///     v.0[0] = -t.pos[0];
+///     None
+/// })
+/// ```
+pub struct Query<D: QueryData, F: QueryFilter = ()> {
+    system: QueryFn<D>,
+    keys: Vec<ComponentKey>,
+    name: Option<String>,
+    // change ticks of the world when the system last ran and when it runs now
+    last_run: u64,
+    this_run: u64,
+    _filter: PhantomData<fn() -> F>,
+}
+
+impl<D: QueryData, F: QueryFilter> Query<D, F> {
+    pub fn new<S>(system: S) -> Box<Self>
+    where
+        S: for<'s> Fn(D::Item<'s>) -> Option<Box<dyn EntityChange>> + Send + Sync + 'static,
+    {
+        let mut keys = Vec::new();
+        D::keys(&mut keys);
+        Box::new(Query {
+            system: Box::new(system),
+            keys,
+            name: None,
+            last_run: 0,
+            this_run: 0,
+            _filter: PhantomData,
+        })
+    }
+
+    pub fn named(mut self: Box<Self>, name: &str) -> Box<Self> {
+        self.name = Some(name.to_string());
+        self
+    }
+}
+
+impl<D: QueryData, F: QueryFilter> QuerySystem for Query<D, F> {
+    fn matches(&self, archetype: &Archetype) -> bool {
+        self.keys.iter().all(|key| archetype.has(key)) && F::matches(archetype)
+    }
+
+    fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(type_name::<Self>())
+    }
+
+    fn prepare(&mut self, world: &World) {
+        self.last_run = self.this_run;
+        self.this_run = world.change_tick();
+    }
+
+    fn run(&self, entity: &Entity, resources: &Resources) -> Option<Box<dyn EntityChange>> {
+        if !F::matches_entity(entity, self.last_run) {
+            return None;
+        }
+        let mut state = D::fetch(entity, resources)?;
+        let returned = (self.system)(D::item(&mut state));
+
+        let mut changes = Vec::new();
+        D::write_back(state, entity, &mut changes);
+        changes.extend(returned);
+        match changes.len() {
+            0 => None,
+            1 => changes.pop(),
+            _ => Some(Changes::new(changes)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::game::entity::Despawn;
+    use crate::game::GameState;
+    use crate::game::schedule::Times;
+    use crate::game::query::*;
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    struct Pos(f32);
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    struct Vel(f32);
+
+    #[derive(Copy, Clone)]
+    struct Frozen;
+
+    #[test]
+    fn query_runs_on_matching_entities() {
+        let mut game = GameState::new();
+        let moving = game.new_entity_mut().insert(Pos(0.)).insert(Vel(2.)).id();
+        let frozen = game.new_entity_mut().insert(Pos(0.)).insert(Vel(2.)).insert(Frozen).id();
+        let still = game.new_entity_mut().insert(Pos(5.)).id();
+
+        game.add_query_system(Times::SimulationTick, Query::<(&Vel, &mut Pos), Without<Frozen>>::new(|(v, p)| {
+            p.0 += v.0;
+            None
+        }));
+        game.sim_tick(Duration::ZERO);
+        game.sim_tick(Duration::ZERO);
+
+        assert_eq!(game.get(moving).unwrap().get::<Pos>(), Some(Pos(4.)));
+        assert_eq!(game.get(frozen).unwrap().get::<Pos>(), Some(Pos(0.)));
+        assert_eq!(game.get(still).unwrap().get::<Pos>(), Some(Pos(5.)));
+    }
+
+    #[test]
+    fn query_systems_can_capture_their_config() {
+        let mut game = GameState::new();
+        let id = game.new_entity_mut().insert(Pos(0.)).insert(Vel(1.)).id();
+        let speed = 2.5;
+        game.add_query_system(Times::SimulationTick, Query::<(&Vel, &mut Pos)>::new(move |(v, p)| {
+            p.0 += v.0 * speed;
+            None
+        }));
+        game.sim_tick(Duration::ZERO);
+
+        assert_eq!(game.get(id).unwrap().get::<Pos>(), Some(Pos(2.5)));
+    }
+
+    #[test]
+    fn unmodified_components_are_not_written() {
+        let mut game = GameState::new();
+        game.new_entity_mut().insert(Pos(1.));
+        game.world.flush();
+        let entity = game.entities().next().unwrap();
+
+        let query = Query::<&mut Pos>::new(|_| None);
+        assert!(query.run(&entity, game.world.resources()).is_none());
+        let query = Query::<&mut Pos>::new(|p| {
+            p.0 = 2.;
+            None
+        });
+        assert!(query.run(&entity, game.world.resources()).is_some());
+    }
+
+    #[test]
+    fn query_systems_can_return_changes() {
+        let mut game = GameState::new();
+        let a = game.new_entity_mut().insert(Pos(-1.)).id();
+        let b = game.new_entity_mut().insert(Pos(1.)).id();
+
+        game.add_query_system(Times::SimulationTick, Query::<(EntityId, &Pos), With<Pos>>::new(|(_, p)| {
+            if p.0 < 0. {
+                Some(Despawn::new())
+            } else {
+                None
+            }
+        }));
+        game.sim_tick(Duration::ZERO);
+
+        assert!(game.get(a).is_none());
+        assert!(game.get(b).is_some());
+    }
+
+    /// The last position a watcher saw.
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    struct Seen(f32);
+
+    #[test]
+    fn only_changed_components_are_reported() {
+        let mut game = GameState::new();
+        let moving = game.new_entity_mut().insert(Pos(0.)).insert(Vel(1.)).insert(Seen(-1.)).id();
+        let still = game.new_entity_mut().insert(Pos(5.)).insert(Vel(0.)).insert(Seen(-1.)).id();
+        game.add_query_system(Times::SimulationTick, Query::<(&Vel, &mut Pos)>::new(|(v, p)| {
+            p.0 += v.0;
+            None
+        }).named("move"));
+        game.add_query_system(Times::SimulationTick, Query::<(&Pos, &mut Seen), Changed<Pos>>::new(|(p, seen)| {
+            seen.0 = p.0;
+            None
+        })).after("move");
+        let seen = |game: &GameState, id| game.get(id).unwrap().get::<Seen>().unwrap().0;
+
+        // everything is new the first time
+        game.sim_tick(Duration::ZERO);
+        assert_eq!(seen(&game, moving), 1.);
+        assert_eq!(seen(&game, still), 5.);
+
+        // after that, only the entity that moves is reported
+        game.world.insert(still, Seen(-1.), None).unwrap();
+        game.sim_tick(Duration::ZERO);
+        assert_eq!(seen(&game, moving), 2.);
+        assert_eq!(seen(&game, still), -1.);
+
+        // writes from outside the systems count too
+        let tick = game.world.change_tick();
+        game.world.insert(still, Pos(6.), None).unwrap();
+        let entity = game.get(still).unwrap();
+        assert!(entity.data().changed_since::<Pos>(tick - 1));
+        assert!(!entity.data().added_since::<Pos>(tick - 1));
+        game.sim_tick(Duration::ZERO);
+        assert_eq!(seen(&game, still), 6.);
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Name(String);
+
+    #[test]
+    fn components_owning_memory_are_compared_by_value() {
+        let mut game = GameState::new();
+        let id = game.new_entity_mut().insert(Name("cat".to_string())).insert(0u32).id();
+        game.add_query_system(Times::SimulationTick, Query::<&mut Name>::new(|name| {
+            if name.0 == "cat" {
+                name.0.push('s');
+            }
+            None
+        }).named("rename"));
+        game.add_query_system(Times::SimulationTick, Query::<&mut u32, Changed<Name>>::new(|n| {
+            *n += 1;
+            None
+        })).after("rename");
+
+        for _ in 0..5 {
+            game.sim_tick(Duration::ZERO);
+        }
+        // the clone handed to the system is not taken for a change, only the rename is
+        let entity = game.get(id).unwrap();
+        assert_eq!(entity.get::<Name>(), Some(Name("cats".to_string())));
+        assert_eq!(entity.get::<u32>(), Some(1));
+    }
+
+    #[test]
+    fn added_components_are_reported_once() {
+        let mut game = GameState::new();
+        let a = game.new_entity_mut().insert(Pos(0.)).id();
+        game.add_query_system(Times::SimulationTick, Query::<&mut Pos, Added<Pos>>::new(|p| {
+            p.0 += 1.;
+            None
+        }));
+        game.sim_tick(Duration::ZERO);
+        game.sim_tick(Duration::ZERO);
+        assert_eq!(game.get(a).unwrap().get::<Pos>(), Some(Pos(1.)));
+
+        let b = game.new_entity_mut().insert(Vel(0.)).id();
+        game.sim_tick(Duration::ZERO);
+        game.world.attach(b, Pos(10.), None).unwrap();
+        game.sim_tick(Duration::ZERO);
+        game.sim_tick(Duration::ZERO);
+        assert_eq!(game.get(a).unwrap().get::<Pos>(), Some(Pos(1.)));
+        assert_eq!(game.get(b).unwrap().get::<Pos>(), Some(Pos(11.)));
+    }
+}
@@ -0,0 +1,117 @@
+use std::any::{type_name, Any, TypeId};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+
+use crate::game::entity::{EntityChange, EntityId};
+use crate::game::world::World;
+
+/// Values shared by the whole game rather than owned by an entity, one per type.
+/// Like the score, the input state or the RNG.
+///
+/// Systems read them while running, and change them through [`SetResource`]
+/// and [`UpdateResource`], which are applied along with the entity changes.
+#[derive(Clone, Default)]
+pub struct Resources {
+    map: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl Resources {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a resource, or replaces the one of the same type.
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) {
+        self.map.insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.map.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+
+    /// A shared handle to the resource, that can outlive the borrow of the world.
+    pub fn get_arc<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.map.get(&TypeId::of::<T>())?.clone().downcast().ok()
+    }
+
+    /// Mutable access to the resource, copying it first if it is still shared.
+    pub fn get_mut<T: Clone + Send + Sync + 'static>(&mut self) -> Option<&mut T> {
+        let value = self.map.get_mut(&TypeId::of::<T>())?;
+        if Arc::get_mut(value).is_none() {
+            let copy: T = value.downcast_ref::<T>()?.clone();
+            *value = Arc::new(copy);
+        }
+        Arc::get_mut(value)?.downcast_mut()
+    }
+
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> bool {
+        self.map.remove(&TypeId::of::<T>()).is_some()
+    }
+
+    pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+        self.map.contains_key(&TypeId::of::<T>())
+    }
+}
+
+/// Sets a resource, whichever entity the change is returned for
+pub struct SetResource<T: Send + Sync + 'static> {
+    value: T,
+}
+
+impl<T: Send + Sync + 'static> EntityChange for SetResource<T> {
+    fn apply(self: Box<Self>, world: &mut World, _entity: EntityId) -> anyhow::Result<()> {
+        world.resources_mut().insert(self.value);
+        Ok(())
+    }
+}
+
+impl<T: Send + Sync + 'static> SetResource<T> {
+    pub fn new(value: T) -> Box<Self> {
+        Box::new(Self { value })
+    }
+}
+
+/// Edits a resource in place, whichever entity the change is returned for.
+/// Unlike [`SetResource`], updates made by several entities in one tick all add up.
+pub struct UpdateResource<T: Clone + Send + Sync + 'static> {
+    update: Box<dyn FnOnce(&mut T) + Send>,
+}
+
+impl<T: Clone + Send + Sync + 'static> EntityChange for UpdateResource<T> {
+    fn apply(self: Box<Self>, world: &mut World, _entity: EntityId) -> anyhow::Result<()> {
+        let value = world.resources_mut().get_mut::<T>()
+            .ok_or_else(|| anyhow!("there is no {} resource", type_name::<T>()))?;
+        (self.update)(value);
+        Ok(())
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> UpdateResource<T> {
+    pub fn new(update: impl FnOnce(&mut T) + Send + 'static) -> Box<Self> {
+        Box::new(Self { update: Box::new(update) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::game::resource::*;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Score(u32);
+
+    #[test]
+    fn resources_are_copied_on_write() {
+        let mut resources = Resources::new();
+        resources.insert(Score(1));
+        let shared = resources.get_arc::<Score>().unwrap();
+        resources.get_mut::<Score>().unwrap().0 += 1;
+
+        assert_eq!(*shared, Score(1));
+        assert_eq!(resources.get::<Score>(), Some(&Score(2)));
+        assert!(resources.get::<u32>().is_none());
+        assert!(resources.remove::<Score>());
+        assert!(!resources.contains::<Score>());
+    }
+}
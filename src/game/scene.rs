@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::game::component::{ComponentKey, ComponentRegistry};
+use crate::game::entity::EntityId;
+use crate::game::hierarchy::{GlobalTransform2D, GlobalTransform3D, Parent};
+use crate::game::world::World;
+
+/// The entities of a world, in a form that can be written to and read from JSON.
+///
+/// Components are saved under the name they were registered with in a [`ComponentRegistry`].
+/// Entity ids are not kept: loading a scene spawns new entities.
+/// A [`Parent`] is saved as the index of the parent in the scene, and points to the new entity once loaded.
+/// World transforms are left out, as they are computed again from the hierarchy.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Scene {
+    pub entities: Vec<SceneEntity>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SceneEntity {
+    /// Index of the parent entity in the scene
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<usize>,
+    pub components: Vec<SceneComponent>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SceneComponent {
+    /// The name the component type was registered under
+    #[serde(rename = "type")]
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    pub value: Value,
+}
+
+impl Scene {
+    /// Saves every entity stored in the world.
+    /// Entities still being built with [`World::new_entity_mut`] are left out until the world is flushed.
+    /// Fails if one of their components is not registered with serde.
+    pub fn save(world: &World, registry: &ComponentRegistry) -> anyhow::Result<Self> {
+        let indices: HashMap<EntityId, usize> = world.entities().enumerate()
+            .map(|(i, entity)| (entity.id(), i))
+            .collect();
+        let mut entities = Vec::with_capacity(world.len());
+        for entity in world.entities() {
+            let data = entity.data();
+            let mut components = Vec::with_capacity(data.keys().len());
+            let mut parent = None;
+            for key in data.keys() {
+                if *key == ComponentKey::of::<Parent>() {
+                    let Parent(id) = entity.get().unwrap();
+                    parent = indices.get(&id).copied();
+                    if parent.is_none() {
+                        log::warn!("the parent of entity {} is gone, it is saved without one", entity.id());
+                    }
+                    continue;
+                }
+                if *key == ComponentKey::of::<GlobalTransform2D>() || *key == ComponentKey::of::<GlobalTransform3D>() {
+                    continue;
+                }
+                let info = registry.info(key)
+                    .with_context(|| format!("cannot save entity {}", entity.id()))?;
+                let value = info.serialize(data, key)
+                    .with_context(|| format!("cannot save {} of entity {}", key, entity.id()))?;
+                components.push(SceneComponent {
+                    name: info.name().to_string(),
+                    label: key.label().map(str::to_string),
+                    value,
+                });
+            }
+            entities.push(SceneEntity { parent, components });
+        }
+        Ok(Scene { entities })
+    }
+
+    /// Spawns the entities of the scene into the world, and returns their ids in scene order.
+    /// Nothing is spawned if one of the components is unknown or cannot be read.
+    pub fn load(&self, world: &mut World, registry: &ComponentRegistry) -> anyhow::Result<Vec<EntityId>> {
+        let mut entities = Vec::with_capacity(self.entities.len());
+        for (i, entity) in self.entities.iter().enumerate() {
+            if entity.parent.is_some_and(|parent| parent >= self.entities.len() || parent == i) {
+                return Err(anyhow!("entity #{} of the scene has no valid parent", i));
+            }
+            let mut components = Vec::with_capacity(entity.components.len());
+            for component in &entity.components {
+                let info = registry.info_by_name(&component.name)
+                    .with_context(|| format!("cannot load entity #{} of the scene", i))?;
+                let value = info.deserialize(component.value.clone())
+                    .with_context(|| format!("cannot load {} of entity #{} of the scene", component.name, i))?;
+                components.push((info.key().with_label(component.label.as_deref()), value));
+            }
+            entities.push(components);
+        }
+
+        let ids: Vec<EntityId> = entities.into_iter()
+            .map(|components| {
+                let builder = world.new_entity_mut();
+                for (key, value) in components {
+                    builder.mut_data().alloc_value(value, key);
+                }
+                builder.id()
+            })
+            .collect();
+        world.flush();
+        // parents are set once every entity has its new id
+        for (entity, id) in self.entities.iter().zip(&ids) {
+            if let Some(parent) = entity.parent {
+                world.attach(*id, Parent(ids[parent]), None)?;
+            }
+        }
+        Ok(ids)
+    }
+
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn from_json(json: &str) -> anyhow::Result<Self> {
+        serde_json::from_str(json).context("not a valid scene")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Serialize};
+
+    use crate::game::component::ComponentRegistry;
+    use crate::game::scene::*;
+    use crate::game::transform::Transform2D;
+    use crate::game::GameState;
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct Player {
+        name: String,
+        speed: f32,
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct Health(u32);
+
+    #[derive(Copy, Clone)]
+    struct Unsaved;
+
+    fn registry() -> ComponentRegistry {
+        let mut registry = ComponentRegistry::new();
+        registry.register_serde::<Player>("Player")
+            .register_serde::<Health>("Health")
+            .register_serde::<Transform2D>("Transform2D");
+        registry
+    }
+
+    #[test]
+    fn scenes_round_trip_through_json() {
+        let mut game = GameState::new();
+        game.new_entity_mut()
+            .insert(Player { name: "cat".to_string(), speed: 2.5 })
+            .insert(Health(3))
+            .insert_labelled(Health(7), "max");
+        game.new_entity_mut().insert(Transform2D { pos: [1., 2.], size: [3., 4.], rot: 0.5 });
+        game.world.flush();
+
+        let json = Scene::save(&game.world, &registry()).unwrap().to_json().unwrap();
+        let mut loaded = GameState::new();
+        let ids = Scene::from_json(&json).unwrap().load(&mut loaded.world, &registry()).unwrap();
+
+        let player = loaded.get(ids[0]).unwrap();
+        assert_eq!(player.get::<Player>(), Some(Player { name: "cat".to_string(), speed: 2.5 }));
+        assert_eq!(player.get::<Health>(), Some(Health(3)));
+        assert_eq!(player.get_labelled::<Health>("max"), Some(Health(7)));
+        let transform = loaded.get(ids[1]).unwrap().get::<Transform2D>().unwrap();
+        assert_eq!((transform.pos, transform.size, transform.rot), ([1., 2.], [3., 4.], 0.5));
+        assert_eq!(Scene::save(&loaded.world, &registry()).unwrap().to_json().unwrap(), json);
+    }
+
+    #[test]
+    fn unknown_components_are_errors() {
+        let mut game = GameState::new();
+        game.new_entity_mut().insert(Health(1)).insert(Unsaved);
+        game.world.flush();
+        let error = Scene::save(&game.world, &registry()).unwrap_err();
+        assert!(format!("{:#}", error).contains("Unsaved"));
+
+        let json = r#"{ "entities": [
+            { "components": [{ "type": "Health", "value": 1 }] },
+            { "components": [{ "type": "Mana", "value": 1 }] }
+        ] }"#;
+        let error = Scene::from_json(json).unwrap().load(&mut game.world, &registry()).unwrap_err();
+        let message = format!("{:#}", error);
+        assert!(message.contains("entity #1") && message.contains("\"Mana\"") && message.contains("Health"));
+        // nothing was spawned
+        assert_eq!(game.world.len(), 1);
+
+        let json = r#"{ "entities": [{ "components": [{ "type": "Health", "value": "full" }] }] }"#;
+        assert!(Scene::from_json(json).unwrap().load(&mut game.world, &registry()).is_err());
+    }
+
+    #[test]
+    fn hierarchies_are_saved_with_scene_indices() {
+        let mut game = GameState::new();
+        game.new_entity_mut().insert(Health(0));
+        let parent = game.new_entity_mut().insert(Transform2D { pos: [1., 0.], size: [1., 1.], rot: 0. }).id();
+        game.new_entity_mut().insert(Transform2D { pos: [0., 1.], size: [1., 1.], rot: 0. }).insert(Parent(parent));
+        game.sim_tick(Duration::ZERO);
+
+        let scene = Scene::save(&game.world, &registry()).unwrap();
+        let json = scene.to_json().unwrap();
+        assert!(!json.contains("GlobalTransform"));
+        // the loaded entities get new ids, which the parent follows
+        let mut loaded = GameState::new();
+        loaded.new_entity_mut().insert(Health(1));
+        let ids = Scene::from_json(&json).unwrap().load(&mut loaded.world, &registry()).unwrap();
+        let (parent, child) = scene.entities.iter().zip(&ids)
+            .find_map(|(entity, id)| Some((ids[entity.parent?], *id)))
+            .unwrap();
+        assert_ne!(parent, ids[0]);
+        assert_eq!(loaded.get(child).unwrap().get::<Parent>(), Some(Parent(parent)));
+        assert_eq!(loaded.world.children(parent), &[child]);
+        loaded.sim_tick(Duration::ZERO);
+        let GlobalTransform2D(t) = loaded.get(child).unwrap().get().unwrap();
+        assert_eq!(t.pos, [1., 1.]);
+        assert_eq!(Scene::save(&loaded.world, &registry()).unwrap().entities.len(), 4);
+
+        let json = r#"{ "entities": [{ "parent": 0, "components": [] }] }"#;
+        assert!(Scene::from_json(json).unwrap().load(&mut loaded.world, &registry()).is_err());
+    }
+}
@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::anyhow;
+
+/// When and how many times should a System be run?
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Times {
+    /// Once, before the first tick or frame
+    Startup,
+    /// Every simulation tick
+    SimulationTick,
+    /// Every rendered frame
+    Frame,
+    /// Every N simulation ticks, starting with the first one
+    EveryTicks(u64),
+    /// Every time N seconds of simulation time have passed
+    EverySeconds(f32),
+}
+
+/// What the systems are being run for.
+#[derive(Copy, Clone)]
+pub(crate) enum Run {
+    Startup,
+    Tick { index: u64, elapsed: Duration, delta: Duration },
+    Frame,
+}
+
+impl Times {
+    pub(crate) fn due(&self, run: Run) -> bool {
+        match (*self, run) {
+            (Times::Startup, Run::Startup) => true,
+            (Times::SimulationTick, Run::Tick { .. }) => true,
+            (Times::Frame, Run::Frame) => true,
+            (Times::EveryTicks(n), Run::Tick { index, .. }) => index % n.max(1) == 0,
+            (Times::EverySeconds(n), Run::Tick { elapsed, delta, .. }) => {
+                if n <= 0. {
+                    return true;
+                }
+                // due when this tick crossed a multiple of the interval
+                let before = (elapsed - delta).as_secs_f64() / n as f64;
+                let after = elapsed.as_secs_f64() / n as f64;
+                before.floor() != after.floor()
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Stages run one after the other, and each one sees the changes made by the ones before.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Stage {
+    Input,
+    Simulate,
+    Physics,
+    PostUpdate,
+}
+
+/// Which system is meant, by its kind and its index in the `GameState` list of that kind.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SystemRef {
+    World(usize),
+    Linear(usize),
+    Query(usize),
+    Quadratic(usize),
+}
+
+/// When, and in what order, a system is run.
+#[derive(Clone, Debug)]
+pub struct SystemConfig {
+    pub times: Times,
+    /// Used in logs, and to order other systems around this one
+    pub name: String,
+    pub stage: Stage,
+    /// Names of the systems that must see this system's changes
+    pub before: Vec<String>,
+    /// Names of the systems whose changes this system must see
+    pub after: Vec<String>,
+}
+
+impl SystemConfig {
+    pub fn new(times: Times, name: impl Into<String>) -> Self {
+        SystemConfig {
+            times,
+            name: name.into(),
+            stage: Stage::Simulate,
+            before: Vec::new(),
+            after: Vec::new(),
+        }
+    }
+}
+
+/// A system, along with when it should be run.
+pub struct Scheduled<S> {
+    pub system: S,
+    pub config: SystemConfig,
+}
+
+impl<S> Scheduled<S> {
+    pub fn new(times: Times, name: impl Into<String>, system: S) -> Self {
+        Scheduled {
+            system,
+            config: SystemConfig::new(times, name),
+        }
+    }
+
+    /// Renames the system, so others can be ordered around it and logs are easier to read.
+    pub fn named(&mut self, name: &str) -> &mut Self {
+        self.config.name = name.to_string();
+        self
+    }
+
+    pub fn in_stage(&mut self, stage: Stage) -> &mut Self {
+        self.config.stage = stage;
+        self
+    }
+
+    /// Runs this system before the named one, so it sees this system's changes.
+    pub fn before(&mut self, name: &str) -> &mut Self {
+        self.config.before.push(name.to_string());
+        self
+    }
+
+    /// Runs this system after the named one, so it sees that system's changes.
+    pub fn after(&mut self, name: &str) -> &mut Self {
+        self.config.after.push(name.to_string());
+        self
+    }
+}
+
+/// Sorts the systems into batches, run one after the other.
+/// The systems of a batch run together, and each batch sees the changes of the ones before it.
+///
+/// Batches follow the stages, and within a stage every system comes after
+/// the systems it must run after. Otherwise systems keep the order they are given in.
+/// Fails if the constraints form a cycle, or a name they use matches no system or several.
+pub fn plan(systems: &[(SystemRef, &SystemConfig)]) -> anyhow::Result<Vec<Vec<SystemRef>>> {
+    let display = |i: usize| systems[i].1.name.clone();
+
+    // names don't have to be unique, unless other systems are ordered around them
+    let mut names: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (i, (_, config)) in systems.iter().enumerate() {
+        names.entry(&config.name).or_default().push(i);
+    }
+    let find = |name: &str| match names.get(name).map(|found| found.as_slice()) {
+        Some([i]) => Ok(*i),
+        Some(_) => Err(anyhow!("more than one system is named {}", name)),
+        None => Err(anyhow!("no system is named {}", name)),
+    };
+
+    // an edge from a to b means a runs first
+    let mut edges = Vec::new();
+    for (i, (_, config)) in systems.iter().enumerate() {
+        for name in &config.before {
+            edges.push((i, find(name)?));
+        }
+        for name in &config.after {
+            edges.push((find(name)?, i));
+        }
+    }
+
+    let mut next: Vec<Vec<usize>> = vec![Vec::new(); systems.len()];
+    let mut waiting_on = vec![0; systems.len()];
+    for (a, b) in edges {
+        let (stage_a, stage_b) = (systems[a].1.stage, systems[b].1.stage);
+        if stage_a > stage_b {
+            return Err(anyhow!(
+                "{} must run before {}, but is in a later stage ({:?} > {:?})",
+                display(a), display(b), stage_a, stage_b,
+            ));
+        }
+        // earlier stages are already run first
+        if stage_a == stage_b {
+            next[a].push(b);
+            waiting_on[b] += 1;
+        }
+    }
+
+    // each system goes one batch after the last system it waits on
+    let mut depth = vec![0; systems.len()];
+    let mut ready: Vec<usize> = (0..systems.len()).filter(|i| waiting_on[*i] == 0).collect();
+    let mut sorted = 0;
+    while let Some(a) = ready.pop() {
+        sorted += 1;
+        for b in next[a].iter().copied() {
+            depth[b] = depth[b].max(depth[a] + 1);
+            waiting_on[b] -= 1;
+            if waiting_on[b] == 0 {
+                ready.push(b);
+            }
+        }
+    }
+    if sorted < systems.len() {
+        let cycle: Vec<String> = (0..systems.len()).filter(|i| waiting_on[*i] > 0).map(display).collect();
+        return Err(anyhow!("systems {} are ordered in a cycle", cycle.join(", ")));
+    }
+
+    let mut batches: Vec<((Stage, usize), Vec<SystemRef>)> = Vec::new();
+    let mut order: Vec<usize> = (0..systems.len()).collect();
+    order.sort_by_key(|i| (systems[*i].1.stage, depth[*i]));
+    for i in order {
+        let key = (systems[i].1.stage, depth[i]);
+        match batches.last_mut() {
+            Some((last, batch)) if *last == key => batch.push(systems[i].0),
+            _ => batches.push((key, vec![systems[i].0])),
+        }
+    }
+    Ok(batches.into_iter().map(|(_, batch)| batch).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::game::schedule::*;
+
+    fn config(name: &str, stage: Stage, after: &[&str]) -> SystemConfig {
+        SystemConfig {
+            stage,
+            after: after.iter().map(|s| s.to_string()).collect(),
+            ..SystemConfig::new(Times::SimulationTick, name)
+        }
+    }
+
+    #[test]
+    fn systems_are_batched_by_stage_and_order() {
+        let configs = [
+            config("physics", Stage::Physics, &[]),
+            config("move", Stage::Simulate, &["input", "ai"]),
+            config("ai", Stage::Simulate, &[]),
+            config("input", Stage::Input, &[]),
+            config("animate", Stage::Simulate, &[]),
+        ];
+        let systems: Vec<_> = configs.iter().enumerate()
+            .map(|(i, c)| (SystemRef::Linear(i), c))
+            .collect();
+        assert_eq!(plan(&systems).unwrap(), vec![
+            vec![SystemRef::Linear(3)],
+            vec![SystemRef::Linear(2), SystemRef::Linear(4)],
+            vec![SystemRef::Linear(1)],
+            vec![SystemRef::Linear(0)],
+        ]);
+    }
+
+    #[test]
+    fn bad_orderings_are_errors() {
+        let cycle = [
+            config("a", Stage::Simulate, &["c"]),
+            config("b", Stage::Simulate, &["a"]),
+            config("c", Stage::Simulate, &["b"]),
+            config("d", Stage::Simulate, &[]),
+        ];
+        let later_stage = [
+            config("a", Stage::Simulate, &["b"]),
+            config("b", Stage::Physics, &[]),
+        ];
+        let unknown = [config("a", Stage::Simulate, &["b"])];
+        let ambiguous = [
+            config("a", Stage::Simulate, &["b"]),
+            config("b", Stage::Simulate, &[]),
+            config("b", Stage::Simulate, &[]),
+        ];
+        for configs in [&cycle[..], &later_stage, &unknown, &ambiguous] {
+            let systems: Vec<_> = configs.iter().enumerate()
+                .map(|(i, c)| (SystemRef::Linear(i), c))
+                .collect();
+            assert!(plan(&systems).is_err());
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use std::sync::Arc;
+
+use crate::game::world::World;
+
+/// An immutable copy of every entity and resource, taken with [`GameState::snapshot`](crate::game::GameState::snapshot).
+///
+/// Components are cloned, while resources are shared until they are written to,
+/// so taking a snapshot only copies the component tables.
+/// Cloning one is free, and it can be restored any number of times.
+#[derive(Clone)]
+pub struct Snapshot {
+    world: Arc<World>,
+    ticks: u64,
+}
+
+impl Snapshot {
+    pub(crate) fn new(world: World, ticks: u64) -> Self {
+        Snapshot {
+            world: Arc::new(world),
+            ticks,
+        }
+    }
+
+    pub fn world(&self) -> &World {
+        &self.world
+    }
+
+    /// Number of ticks that had been run when the snapshot was taken.
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::game::entity::{Change, Despawn, Spawn};
+    use crate::game::query::{Changed, Query};
+    use crate::game::resource::UpdateResource;
+    use crate::game::schedule::Times;
+    use crate::game::GameState;
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    struct Counter(u32);
+
+    fn state(game: &GameState) -> Vec<(String, Option<u32>)> {
+        game.entities().map(|e| (e.id().to_string(), e.get::<u32>())).collect()
+    }
+
+    #[test]
+    fn restoring_rolls_everything_back() {
+        let mut game = GameState::new();
+        game.insert_resource(Counter(0));
+        for i in 0..3u32 {
+            game.new_entity_mut().insert(i);
+        }
+        game.add_linear_system(Times::SimulationTick, |entity, _| {
+            let i = entity.get::<u32>()?;
+            Some(match i % 4 {
+                0 => Despawn::new(),
+                1 => Spawn::new(move |e| { e.insert(i * 10); }),
+                _ => Change::new(i + 1),
+            })
+        });
+        game.add_linear_system(Times::SimulationTick, |_, _| {
+            Some(UpdateResource::new(|c: &mut Counter| c.0 += 1))
+        });
+
+        game.sim_tick(Duration::from_secs(1));
+        let snapshot = game.snapshot();
+        let before = (state(&game), *game.resource::<Counter>().unwrap(), game.time());
+
+        game.sim_tick(Duration::from_secs(1));
+        let after_one_tick = (state(&game), *game.resource::<Counter>().unwrap(), game.time());
+        game.sim_tick(Duration::from_secs(1));
+        assert_ne!(state(&game), before.0);
+
+        game.restore(&snapshot);
+        assert_eq!((state(&game), *game.resource::<Counter>().unwrap(), game.time()), before);
+        assert_eq!(snapshot.ticks(), 1);
+
+        // the simulation plays out the same way again, spawned ids included
+        game.sim_tick(Duration::from_secs(1));
+        assert_eq!((state(&game), *game.resource::<Counter>().unwrap(), game.time()), after_one_tick);
+        assert_eq!(snapshot.world().len(), before.0.len());
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    struct Hp(u32);
+
+    #[test]
+    fn writes_after_a_restore_are_seen() {
+        let mut game = GameState::new();
+        let id = game.new_entity_mut().insert(Hp(10)).insert(Counter(0)).id();
+        game.add_query_system(Times::SimulationTick, Query::<&mut Counter, Changed<Hp>>::new(|c| {
+            c.0 += 1;
+            None
+        }));
+        game.sim_tick(Duration::ZERO);
+        let snapshot = game.snapshot();
+        for _ in 0..3 {
+            game.sim_tick(Duration::ZERO);
+        }
+
+        game.restore(&snapshot);
+        game.world.insert(id, Hp(5), None).unwrap();
+        for _ in 0..3 {
+            game.sim_tick(Duration::ZERO);
+        }
+        // once when it was spawned, and once for the write
+        assert_eq!(game.get(id).unwrap().get::<Counter>(), Some(Counter(2)));
+    }
+}
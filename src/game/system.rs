@@ -0,0 +1,107 @@
+use std::any::type_name;
+
+use crate::game::entity::{Entity, EntityChange};
+use crate::game::resource::Resources;
+use crate::game::world::World;
+
+/// A system run on single entities. It may be run on several entities at once from different threads,
+/// so its own state can only be changed in [`System::prepare`].
+///
+/// Closures taking `(&Entity, &Resources)` are systems too.
+pub trait System: Send + Sync {
+    /// Used in logs, and by default as the name other systems are ordered around.
+    fn name(&self) -> &str {
+        type_name::<Self>()
+    }
+
+    /// Called before the system is run on the entities, with exclusive access to its state.
+    /// Useful for timers, or looking things up once instead of once per entity.
+    fn prepare(&mut self, _world: &World) {}
+
+    fn run(&self, entity: &Entity, resources: &Resources) -> Option<Box<dyn EntityChange>>;
+}
+
+impl<F> System for F
+where
+    F: Fn(&Entity, &Resources) -> Option<Box<dyn EntityChange>> + Send + Sync,
+{
+    fn run(&self, entity: &Entity, resources: &Resources) -> Option<Box<dyn EntityChange>> {
+        self(entity, resources)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::game::entity::{Change, EntityId};
+    use crate::game::schedule::Times;
+    use crate::game::system::*;
+    use crate::game::GameState;
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    struct Pos(f32);
+
+    #[derive(Copy, Clone)]
+    struct Follower;
+
+    /// Looks up where the target is once per tick, instead of once per follower.
+    struct Follow {
+        target: EntityId,
+        target_pos: Option<Pos>,
+    }
+
+    impl System for Follow {
+        fn name(&self) -> &str {
+            "follow"
+        }
+
+        fn prepare(&mut self, world: &World) {
+            self.target_pos = world.get(self.target).and_then(|e| e.get());
+        }
+
+        fn run(&self, entity: &Entity, _resources: &Resources) -> Option<Box<dyn EntityChange>> {
+            if !entity.has::<Follower>() {
+                return None;
+            }
+            Some(Change::new(self.target_pos?))
+        }
+    }
+
+    #[test]
+    fn systems_keep_their_own_state() {
+        let mut game = GameState::new();
+        let target = game.new_entity_mut().insert(Pos(0.)).id();
+
+        // captures its speed
+        let speed = 2.;
+        game.add_linear_system(Times::SimulationTick, move |entity, _| {
+            if entity.has::<Follower>() {
+                return None;
+            }
+            Some(Change::new(Pos(entity.get::<Pos>()?.0 + speed)))
+        });
+        game.add_system(Times::SimulationTick, Follow { target, target_pos: None }).after("spawner");
+        // spawns a follower every other tick
+        let mut timer = 0;
+        game.add_world_system(Times::SimulationTick, move |world| {
+            timer += 1;
+            if timer % 2 == 0 {
+                world.new_entity_mut().insert(Pos(-1.)).insert(Follower);
+            }
+        }).named("spawner");
+
+        for _ in 0..4 {
+            game.sim_tick(Duration::ZERO);
+        }
+
+        assert_eq!(game.linear_systems[1].config.name, "follow");
+        assert!(game.linear_systems[0].config.name.contains("closure"));
+        // running after the spawner also puts it after the movement, so it sees the moved target
+        let followers: Vec<Pos> = game.entities()
+            .filter(|e| e.has::<Follower>())
+            .filter_map(|e| e.get())
+            .collect();
+        assert_eq!(followers, vec![Pos(8.), Pos(8.)]);
+    }
+}
@@ -0,0 +1,160 @@
+use std::time::Duration;
+
+/// Simulation time, kept as a resource so systems can read it.
+/// Setting the `Time` resource from a system changes the scale from the next tick on.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Time {
+    /// Simulation time since the last tick, already scaled
+    pub delta: Duration,
+    /// Simulation time since the first tick
+    pub elapsed: Duration,
+    /// Index of the current tick, counting from 0
+    pub tick: u64,
+    /// How fast the simulation runs compared to real time. 0 pauses it.
+    pub scale: f32,
+}
+
+impl Default for Time {
+    fn default() -> Self {
+        Time {
+            delta: Duration::ZERO,
+            elapsed: Duration::ZERO,
+            tick: 0,
+            scale: 1.,
+        }
+    }
+}
+
+impl Time {
+    pub fn delta_secs(&self) -> f32 {
+        self.delta.as_secs_f32()
+    }
+
+    pub fn elapsed_secs(&self) -> f32 {
+        self.elapsed.as_secs_f32()
+    }
+
+    /// The time of the tick with the given index, `real_delta` after this one.
+    pub(crate) fn advance(&self, tick: u64, real_delta: Duration) -> Time {
+        let delta = Duration::try_from_secs_f32(real_delta.as_secs_f32() * self.scale.max(0.))
+            .unwrap_or_else(|_| {
+                log::warn!("time scale {} is too large, the tick is capped to {:?}", self.scale, Duration::MAX);
+                Duration::MAX
+            });
+        Time {
+            delta,
+            elapsed: self.elapsed.saturating_add(delta),
+            tick,
+            scale: self.scale,
+        }
+    }
+}
+
+/// Turns the real time between frames into a whole number of simulation ticks of a fixed length.
+/// Time left over is carried to the next frame, and how far it is into the next tick is the
+/// [`alpha`](FixedTimestep::alpha) renderers blend the last two ticks with.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct FixedTimestep {
+    tick: Duration,
+    /// Most ticks run for one frame. When the simulation can't keep up, the time beyond that is dropped,
+    /// which slows the game down rather than making every frame take longer than the one before.
+    pub max_ticks: u32,
+    accumulator: Duration,
+}
+
+impl Default for FixedTimestep {
+    fn default() -> Self {
+        FixedTimestep::from_rate(30.)
+    }
+}
+
+impl FixedTimestep {
+    pub fn new(tick: Duration) -> Self {
+        assert!(!tick.is_zero(), "ticks can't be zero seconds long");
+        FixedTimestep {
+            tick,
+            max_ticks: 5,
+            accumulator: Duration::ZERO,
+        }
+    }
+
+    /// A timestep of `rate` ticks per second.
+    pub fn from_rate(rate: f64) -> Self {
+        assert!(rate > 0. && rate.is_finite(), "tick rates must be positive and finite, not {}", rate);
+        FixedTimestep::new(Duration::from_secs_f64(1. / rate))
+    }
+
+    /// Real time a tick stands for.
+    pub fn tick(&self) -> Duration {
+        self.tick
+    }
+
+    pub fn rate(&self) -> f64 {
+        1. / self.tick.as_secs_f64()
+    }
+
+    /// Adds the real time since the last frame, and returns how many ticks are due.
+    pub fn ticks_for(&mut self, real_delta: Duration) -> u32 {
+        let accumulated = (self.accumulator + real_delta).as_nanos();
+        let tick = self.tick.as_nanos();
+        let due = accumulated / tick;
+        if due > self.max_ticks as u128 {
+            log::debug!("dropping {} ticks the simulation can't catch up on", due - self.max_ticks as u128);
+        }
+        self.accumulator = Duration::from_nanos((accumulated % tick) as u64);
+        due.min(self.max_ticks as u128) as u32
+    }
+
+    /// Drops the time carried over to the next tick.
+    pub fn reset(&mut self) {
+        self.accumulator = Duration::ZERO;
+    }
+
+    /// How far the carried time is into the next tick, from 0 to 1.
+    pub fn alpha(&self) -> f32 {
+        self.accumulator.as_secs_f32() / self.tick.as_secs_f32()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::game::time::*;
+
+    #[test]
+    fn huge_scales_are_capped() {
+        let time = Time { scale: f32::INFINITY, ..Time::default() };
+        let next = time.advance(1, Duration::from_millis(10));
+        assert_eq!((next.delta, next.elapsed), (Duration::MAX, Duration::MAX));
+        assert_eq!(next.advance(2, Duration::from_millis(10)).elapsed, Duration::MAX);
+        let paused = Time { scale: f32::NAN, ..Time::default() };
+        assert_eq!(paused.advance(1, Duration::from_millis(10)).delta, Duration::ZERO);
+    }
+
+    #[test]
+    fn leftover_time_is_carried_over() {
+        let mut step = FixedTimestep::new(Duration::from_millis(10));
+        assert_eq!(step.ticks_for(Duration::from_millis(4)), 0);
+        assert!((step.alpha() - 0.4).abs() < 1e-6);
+        // a slow frame catches up
+        assert_eq!(step.ticks_for(Duration::from_millis(27)), 3);
+        assert!((step.alpha() - 0.1).abs() < 1e-6);
+        assert_eq!(step.ticks_for(Duration::from_millis(9)), 1);
+        assert_eq!(step.alpha(), 0.);
+    }
+
+    #[test]
+    fn catching_up_is_capped() {
+        let mut step = FixedTimestep::from_rate(100.);
+        step.max_ticks = 4;
+        assert_eq!(step.ticks_for(Duration::from_millis(1005)), 4);
+        // the rest of the time is dropped, not owed to the next frames
+        assert!((step.alpha() - 0.5).abs() < 1e-3);
+        assert_eq!(step.ticks_for(Duration::from_millis(10)), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "must be positive")]
+    fn zero_rates_are_rejected() {
+        FixedTimestep::from_rate(0.);
+    }
+}
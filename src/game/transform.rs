@@ -1,27 +1,29 @@
 use std::f32::consts::PI;
 use std::fmt::{Display, Formatter};
+#[cfg(feature = "graphics")]
 use std::mem;
 
 use bytemuck::{Pod, Zeroable};
 use cgmath::{Matrix4, Quaternion, Vector3};
 use cgmath::num_traits::Pow;
+#[cfg(feature = "graphics")]
 use mem_macros::size_of;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "graphics")]
 use wgpu::BufferAddress;
 
-use crate::game::entity::{Component, Entity};
-use crate::util::arena::ComponentArena;
+use crate::game::entity::{Component, EntityBuilder, EntityData};
+use crate::game::hierarchy::{GlobalTransform2D, GlobalTransform3D};
 use crate::util::Either;
 
-pub const TRANSFORM_COMP_NAME: &str = "pos";
-
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Transform2D {
     pub pos: [f32; 2],
     pub size: [f32; 2],
     pub rot: f32,
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Transform3D {
     pub pos: [f32; 3],
     pub size: [f32; 3],
@@ -30,13 +32,35 @@ pub struct Transform3D {
 
 impl Transform2D {
     pub fn dist(t1: Transform2D, t2: Transform2D) -> f32 {
-        return f32::sqrt((t2.pos[0] - t1.pos[0]).pow(2) + (t2.pos[1] - t1.pos[1]).pow(2))
+        f32::sqrt((t2.pos[0] - t1.pos[0]).pow(2) + (t2.pos[1] - t1.pos[1]).pow(2))
+    }
+
+    /// Where a transform relative to this one ends up: its position is scaled and rotated by this one,
+    /// then moved by it. Sizes multiply and rotations add up.
+    pub fn compose(&self, child: &Transform2D) -> Transform2D {
+        let (sin_r, cos_r) = (self.rot * PI).sin_cos();
+        let x = child.pos[0] * self.size[0];
+        let y = child.pos[1] * self.size[1];
+        Transform2D {
+            pos: [self.pos[0] + x * cos_r - y * sin_r, self.pos[1] + x * sin_r + y * cos_r],
+            size: [self.size[0] * child.size[0], self.size[1] * child.size[1]],
+            rot: self.rot + child.rot,
+        }
+    }
+
+    /// Blends this transform into `to`: `t` of 0 gives this one back, 1 gives `to`.
+    pub fn lerp(&self, to: &Transform2D, t: f32) -> Transform2D {
+        Transform2D {
+            pos: lerp(self.pos, to.pos, t),
+            size: lerp(self.size, to.size, t),
+            rot: self.rot + (to.rot - self.rot) * t,
+        }
     }
 
     pub fn to_raw(&self) -> RawTransform2D {
         let cos_r = (self.rot * PI).cos();
         let sin_r = (self.rot * PI).sin();
-        return RawTransform2D {
+        RawTransform2D {
             offset: self.pos,
             matrix: [
                 [self.size[0] * cos_r, -sin_r],
@@ -48,12 +72,33 @@ impl Transform2D {
 
 impl Transform3D {
     pub fn dist(t1: Transform3D, t2: Transform3D) -> f32 {
-        return f32::sqrt((t2.pos[0] - t1.pos[0]).pow(2)
+        f32::sqrt((t2.pos[0] - t1.pos[0]).pow(2)
             + (t2.pos[1] - t1.pos[1]).pow(2) + (t2.pos[2] - t1.pos[2]).pow(2))
     }
 
+    /// Where a transform relative to this one ends up: its position is scaled and rotated by this one,
+    /// then moved by it. Sizes multiply and rotations are chained.
+    pub fn compose(&self, child: &Transform3D) -> Transform3D {
+        let scaled = Vector3::from(child.pos).zip(Vector3::from(self.size), |p, s| p * s);
+        let pos = Vector3::from(self.pos) + self.rotation * scaled;
+        Transform3D {
+            pos: pos.into(),
+            size: Vector3::from(self.size).zip(Vector3::from(child.size), |a, b| a * b).into(),
+            rotation: self.rotation * child.rotation,
+        }
+    }
+
+    /// Blends this transform into `to`: `t` of 0 gives this one back, 1 gives `to`.
+    pub fn lerp(&self, to: &Transform3D, t: f32) -> Transform3D {
+        Transform3D {
+            pos: lerp(self.pos, to.pos, t),
+            size: lerp(self.size, to.size, t),
+            rotation: self.rotation.slerp(to.rotation, t),
+        }
+    }
+
     pub fn to_raw(&self) -> RawTransform3D {
-        return RawTransform3D {
+        RawTransform3D {
             model: (Matrix4::from_translation(Vector3::from(self.pos))
                 * Matrix4::from(self.rotation)).into(),
             normal: cgmath::Matrix3::from(self.rotation).into(),
@@ -61,21 +106,43 @@ impl Transform3D {
     }
 }
 
-pub fn get_pos(arena: &ComponentArena) -> Option<Either<Transform2D, Transform3D>> {
-    let l = arena.get_length(TRANSFORM_COMP_NAME)?;
+fn lerp<const N: usize>(from: [f32; N], to: [f32; N], t: f32) -> [f32; N] {
+    let mut out = from;
+    for (o, to) in out.iter_mut().zip(to) {
+        *o += (to - *o) * t;
+    }
+    out
+}
 
-    if l == mem::size_of::<Transform2D>() {
-        let t: Transform2D = arena.get(TRANSFORM_COMP_NAME)?;
-        Some(Either::This(t))
+/// Blends two world transforms of an entity, if they are of the same kind. Otherwise `to` is returned.
+pub fn lerp_pos(
+    from: Either<Transform2D, Transform3D>,
+    to: Either<Transform2D, Transform3D>,
+    t: f32,
+) -> Either<Transform2D, Transform3D> {
+    match (from, to) {
+        (Either::This(from), Either::This(to)) => Either::This(from.lerp(&to, t)),
+        (Either::That(from), Either::That(to)) => Either::That(from.lerp(&to, t)),
+        (_, to) => to,
+    }
+}
 
-    } else if l == mem::size_of::<Transform3D>() {
-        let t: Transform3D = arena.get(TRANSFORM_COMP_NAME)?;
-        Some(Either::That(t))
+pub fn get_pos(data: &EntityData) -> Option<Either<Transform2D, Transform3D>> {
+    if let Some(t) = data.get::<Transform2D>() {
+        return Some(Either::This(t));
+    }
+    data.get::<Transform3D>().map(Either::That)
+}
 
-    } else {
-        // unknown size of transform found
-        None
+/// Like [`get_pos`], but for children it is their position in the world, rather than relative to their parent.
+pub fn get_world_pos(data: &EntityData) -> Option<Either<Transform2D, Transform3D>> {
+    if let Some(GlobalTransform2D(t)) = data.get() {
+        return Some(Either::This(t));
+    }
+    if let Some(GlobalTransform3D(t)) = data.get() {
+        return Some(Either::That(t));
     }
+    get_pos(data)
 }
 
 #[repr(C)]
@@ -92,6 +159,7 @@ pub struct RawTransform3D {
     pub normal: [[f32; 3]; 3],
 }
 
+#[cfg(feature = "graphics")]
 impl RawTransform2D {
     pub fn desc<'a, const LOC: u32>() -> wgpu::VertexBufferLayout<'a> {
         wgpu::VertexBufferLayout {
@@ -118,6 +186,7 @@ impl RawTransform2D {
     }
 }
 
+#[cfg(feature = "graphics")]
 impl RawTransform3D {
     pub fn desc<'a, const LOC: u32>() -> wgpu::VertexBufferLayout<'a> {
         wgpu::VertexBufferLayout {
@@ -169,14 +238,14 @@ impl RawTransform3D {
 
 
 impl Component for Transform2D {
-    fn to_entity(self, entity: &mut Entity) {
-        entity.mut_data().alloc(self, TRANSFORM_COMP_NAME)
+    fn to_entity(self, entity: &mut EntityBuilder) {
+        entity.insert(self);
     }
 }
 
 impl Component for Transform3D {
-    fn to_entity(self, entity: &mut Entity) {
-        entity.mut_data().alloc(self, TRANSFORM_COMP_NAME)
+    fn to_entity(self, entity: &mut EntityBuilder) {
+        entity.insert(self);
     }
 }
 
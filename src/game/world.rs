@@ -0,0 +1,475 @@
+use std::collections::HashMap;
+use std::mem;
+
+use anyhow::anyhow;
+
+use crate::game::archetype::{read_component, Archetype, ArchetypeKey, ComponentTicks};
+use crate::game::component::{ComponentKey, ComponentValue};
+use crate::game::entity::{Entity, EntityBuilder, EntityChange, EntityId, EntityMut};
+use crate::game::hierarchy::Parent;
+use crate::game::resource::Resources;
+
+/// Fails if the value is not of the type the key is for.
+fn check_type(key: &ComponentKey, value: &ComponentValue) -> anyhow::Result<()> {
+    if value.ty().type_id() != key.type_id() {
+        return Err(anyhow!("a {:?} cannot be stored as {}!", value.ty(), key));
+    }
+    Ok(())
+}
+
+/// Where an entity's components live.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct EntityLocation {
+    pub archetype: usize,
+    pub row: usize,
+}
+
+/// Book-keeping for one entity index.
+/// The generation is bumped every time the index is freed, which invalidates old handles.
+#[derive(Clone)]
+struct EntityMeta {
+    generation: u32,
+    location: Option<EntityLocation>,
+}
+
+/// Storage for every entity in the game, grouped into [`Archetype`] tables.
+#[derive(Clone)]
+pub struct World {
+    archetypes: Vec<Archetype>,
+    archetype_index: HashMap<ArchetypeKey, usize>,
+    metas: Vec<EntityMeta>,
+    free_indices: Vec<u32>,
+    len: usize,
+    // entities that have been created but not yet moved into an archetype
+    spawn_queue: Vec<EntityBuilder>,
+    resources: Resources,
+    // every component write is stamped with this, see `ComponentTicks`
+    change_tick: u64,
+    // the entities with a `Parent` component, by parent
+    children: HashMap<EntityId, Vec<EntityId>>,
+}
+
+impl Default for World {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl World {
+    pub fn new() -> Self {
+        World {
+            archetypes: Vec::new(),
+            archetype_index: HashMap::new(),
+            metas: Vec::new(),
+            free_indices: Vec::new(),
+            len: 0,
+            spawn_queue: Vec::new(),
+            resources: Resources::new(),
+            change_tick: 1,
+            children: HashMap::new(),
+        }
+    }
+
+    /// The tick components written to right now are marked as changed at.
+    pub fn change_tick(&self) -> u64 {
+        self.change_tick
+    }
+
+    /// Starts a new change tick, so later writes can be told apart from the earlier ones.
+    /// Returns the new tick.
+    pub fn increment_change_tick(&mut self) -> u64 {
+        self.change_tick += 1;
+        self.change_tick
+    }
+
+    /// Moves the change tick up to `tick` if it is behind, so ticks keep growing when an older world
+    /// replaces a newer one, and systems still see the writes made from then on.
+    pub(crate) fn catch_up_change_tick(&mut self, tick: u64) {
+        self.change_tick = self.change_tick.max(tick);
+    }
+
+    fn alloc_id(&mut self) -> EntityId {
+        if let Some(index) = self.free_indices.pop() {
+            let generation = self.metas[index as usize].generation;
+            return EntityId::new(index, generation);
+        }
+        self.metas.push(EntityMeta { generation: 0, location: None });
+        EntityId::new((self.metas.len() - 1) as u32, 0)
+    }
+
+    /// Starts building a new entity.
+    /// It is stored in the world the next time [`World::flush`] is called.
+    pub fn new_entity_mut(&mut self) -> &mut EntityBuilder {
+        let id = self.alloc_id();
+        self.spawn_queue.push(EntityBuilder::new(id));
+        self.spawn_queue.last_mut().unwrap()
+    }
+
+    /// Moves every entity built with [`World::new_entity_mut`] into its archetype.
+    pub fn flush(&mut self) {
+        for builder in mem::take(&mut self.spawn_queue) {
+            self.spawn(builder);
+        }
+    }
+
+    fn spawn(&mut self, builder: EntityBuilder) {
+        let id = builder.id();
+        let ticks = ComponentTicks::new(self.change_tick);
+        let components = builder.into_components().into_iter()
+            .map(|(key, value)| (key, value, ticks))
+            .collect();
+        self.place(id, components);
+    }
+
+    /// Puts an entity that is not stored anywhere into the archetype matching its components.
+    fn place(&mut self, id: EntityId, components: Vec<(ComponentKey, ComponentValue, ComponentTicks)>) {
+        if let Some(parent) = parent_of(components.iter().map(|(key, value, _)| (key, value))) {
+            self.link(id, parent);
+        }
+        let archetype = self.archetype_for(&components);
+        let row = self.archetypes[archetype].push(id, components);
+        self.metas[id.index() as usize].location = Some(EntityLocation { archetype, row });
+        self.len += 1;
+    }
+
+    /// Removes an entity from its archetype, handing back its components.
+    fn take(&mut self, id: EntityId) -> Option<Vec<(ComponentKey, ComponentValue, ComponentTicks)>> {
+        let loc = self.location(id)?;
+        self.metas[id.index() as usize].location = None;
+        self.len -= 1;
+        let (components, moved) = self.archetypes[loc.archetype].take(loc.row);
+        if let Some(moved) = moved {
+            self.metas[moved.index() as usize].location = Some(loc);
+        }
+        if let Some(parent) = parent_of(components.iter().map(|(key, value, _)| (key, value))) {
+            self.unlink(id, parent);
+        }
+        Some(components)
+    }
+
+    fn link(&mut self, child: EntityId, parent: EntityId) {
+        self.children.entry(parent).or_default().push(child);
+    }
+
+    fn unlink(&mut self, child: EntityId, parent: EntityId) {
+        if let Some(children) = self.children.get_mut(&parent) {
+            children.retain(|id| *id != child);
+            if children.is_empty() {
+                self.children.remove(&parent);
+            }
+        }
+    }
+
+    /// The entities whose [`Parent`] is the given one.
+    pub fn children(&self, parent: EntityId) -> &[EntityId] {
+        self.children.get(&parent).map_or(&[], Vec::as_slice)
+    }
+
+    /// Removes an entity and all of its components from the world, along with its children.
+    /// Every [`EntityId`] pointing to them becomes stale.
+    pub fn despawn(&mut self, id: EntityId) -> anyhow::Result<()> {
+        self.take(id)
+            .ok_or_else(|| anyhow!("entity {} does not exist!", id))?;
+        let meta = &mut self.metas[id.index() as usize];
+        meta.generation = meta.generation.wrapping_add(1);
+        self.free_indices.push(id.index());
+        for child in self.children(id).to_vec() {
+            self.despawn(child)?;
+        }
+        Ok(())
+    }
+
+    /// Adds a component to an entity, moving it to a new archetype.
+    /// If the entity already has a component with this key it is replaced in place,
+    /// like [`World::insert_value`] does, and keeps the tick it was added at.
+    pub fn attach_value(&mut self, id: EntityId, key: ComponentKey, value: ComponentValue) -> anyhow::Result<()> {
+        check_type(&key, &value)?;
+        let loc = self.location(id)
+            .ok_or_else(|| anyhow!("entity {} does not exist!", id))?;
+        if self.archetypes[loc.archetype].has(&key) {
+            return self.insert_value(id, &key, value);
+        }
+        let mut components = self.take(id).unwrap();
+        components.push((key, value, ComponentTicks::new(self.change_tick)));
+        self.place(id, components);
+        Ok(())
+    }
+
+    pub fn attach<T: Clone + Send + Sync + 'static>(&mut self, id: EntityId, data: T, label: Option<&str>) -> anyhow::Result<()> {
+        self.attach_value(id, ComponentKey::new::<T>(label), ComponentValue::new(data))
+    }
+
+    /// Removes a component from an entity, moving it to a new archetype.
+    pub fn detach(&mut self, id: EntityId, key: &ComponentKey) -> anyhow::Result<()> {
+        let loc = self.location(id)
+            .ok_or_else(|| anyhow!("entity {} does not exist!", id))?;
+        if !self.archetypes[loc.archetype].has(key) {
+            return Err(anyhow!("entity {} has no component {}!", id, key));
+        }
+        let mut components = self.take(id).unwrap();
+        components.retain(|(k, ..)| k != key);
+        self.place(id, components);
+        Ok(())
+    }
+
+    fn archetype_for(&mut self, components: &[(ComponentKey, ComponentValue, ComponentTicks)]) -> usize {
+        let mut key: ArchetypeKey = components.iter()
+            .map(|(key, ..)| key.clone())
+            .collect();
+        key.sort();
+        if let Some(i) = self.archetype_index.get(&key) {
+            return *i;
+        }
+        let columns = components.iter()
+            .map(|(key, value, _)| (key.clone(), *value.ty()))
+            .collect();
+        self.archetypes.push(Archetype::new(columns));
+        self.archetype_index.insert(key, self.archetypes.len() - 1);
+        self.archetypes.len() - 1
+    }
+
+    pub fn resources(&self) -> &Resources {
+        &self.resources
+    }
+
+    pub fn resources_mut(&mut self) -> &mut Resources {
+        &mut self.resources
+    }
+
+    pub fn archetypes(&self) -> &[Archetype] {
+        &self.archetypes
+    }
+
+    /// Where the entity is stored.
+    /// Returns None if the handle is stale or the entity has not been flushed yet.
+    pub fn location(&self, id: EntityId) -> Option<EntityLocation> {
+        let meta = self.metas.get(id.index() as usize)?;
+        if meta.generation != id.generation() {
+            return None;
+        }
+        meta.location
+    }
+
+    pub fn contains(&self, id: EntityId) -> bool {
+        self.location(id).is_some()
+    }
+
+    pub fn get(&self, id: EntityId) -> Option<Entity<'_>> {
+        let loc = self.location(id)?;
+        Some(Entity::new(id, &self.archetypes[loc.archetype], loc.row))
+    }
+
+    pub fn get_mut(&mut self, id: EntityId) -> Option<EntityMut<'_>> {
+        self.location(id)?;
+        Some(EntityMut::new(id, self))
+    }
+
+    /// Number of entities stored in archetypes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Iterates every entity, one archetype at a time.
+    pub fn entities(&self) -> impl Iterator<Item = Entity<'_>> {
+        self.archetypes.iter().flat_map(|archetype| archetype.iter())
+    }
+
+    /// Replaces a component of an entity, dropping the old value, and marks it as changed.
+    pub fn insert_value(&mut self, id: EntityId, key: &ComponentKey, value: ComponentValue) -> anyhow::Result<()> {
+        let loc = self.location(id)
+            .ok_or_else(|| anyhow!("entity {} does not exist!", id))?;
+        let archetype = &mut self.archetypes[loc.archetype];
+        if !archetype.has(key) {
+            return Err(anyhow!("entity {} has no component {}!", id, key));
+        }
+        let new_parent = parent_of([(key, &value)]);
+        let old_parent = new_parent.and_then(|_| read_component::<Parent>(archetype.get_bytes(loc.row, key)?));
+        archetype.set(loc.row, key, value, self.change_tick)?;
+        if let (Some(Parent(old)), Some(new)) = (old_parent, new_parent) {
+            self.unlink(id, old);
+            self.link(id, new);
+        }
+        Ok(())
+    }
+
+    pub fn insert<T: Clone + Send + Sync + 'static>(&mut self, id: EntityId, data: T, label: Option<&str>) -> anyhow::Result<()> {
+        self.insert_value(id, &ComponentKey::new::<T>(label), ComponentValue::new(data))
+    }
+
+    pub fn resolve_changes(&mut self, id: EntityId, changes: Box<dyn EntityChange>) -> anyhow::Result<()> {
+        changes.apply(self, id)
+    }
+}
+
+/// The parent set by the components, if there is one.
+fn parent_of<'c>(components: impl IntoIterator<Item = (&'c ComponentKey, &'c ComponentValue)>) -> Option<EntityId> {
+    let (_, value) = components.into_iter().find(|(key, _)| **key == ComponentKey::of::<Parent>())?;
+    read_component::<Parent>(value.bytes()).map(|Parent(parent)| parent)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::game::entity::{Attach, Change, Despawn, Detach, EntityId, Spawn};
+    use crate::game::world::*;
+
+    fn world_with(values: &[u32]) -> (World, Vec<EntityId>) {
+        let mut world = World::new();
+        let ids = values.iter()
+            .map(|v| {
+                let e = world.new_entity_mut();
+                e.insert(*v);
+                e.id()
+            })
+            .collect();
+        world.flush();
+        (world, ids)
+    }
+
+    #[test]
+    fn entities_share_archetypes() {
+        let mut world = World::new();
+        let mut ids = Vec::new();
+        for i in 0..3u32 {
+            let e = world.new_entity_mut();
+            e.insert(i);
+            if i == 1 {
+                e.insert(1.5f32);
+            }
+            ids.push(e.id());
+        }
+        // nothing is stored until the world is flushed
+        assert_eq!(world.len(), 0);
+        assert!(world.get(ids[0]).is_none());
+        world.flush();
+
+        assert_eq!(world.len(), 3);
+        assert_eq!(world.archetypes().len(), 2);
+        assert_eq!(world.get(ids[1]).unwrap().get::<f32>(), Some(1.5));
+        let order: Vec<EntityId> = world.entities().map(|e| e.id()).collect();
+        assert_eq!(order, vec![ids[0], ids[2], ids[1]]);
+    }
+
+    #[test]
+    fn changes_are_written_in_place() {
+        let (mut world, ids) = world_with(&[1]);
+
+        world.resolve_changes(ids[0], Change::new(5u32)).unwrap();
+        assert_eq!(world.get(ids[0]).unwrap().get::<u32>(), Some(5));
+        assert!(world.resolve_changes(ids[0], Change::labelled(5u32, "b")).is_err());
+        assert!(world.resolve_changes(ids[0], Change::new(5u64)).is_err());
+    }
+
+    #[test]
+    fn structural_changes() {
+        let (mut world, ids) = world_with(&[0, 1, 2]);
+
+        world.resolve_changes(ids[0], Attach::new(2.5f32)).unwrap();
+        assert_eq!(world.get(ids[0]).unwrap().get::<f32>(), Some(2.5));
+        assert_eq!(world.get(ids[0]).unwrap().get::<u32>(), Some(0));
+        // the entity that was swapped into the old row can still be found
+        assert_eq!(world.get(ids[2]).unwrap().get::<u32>(), Some(2));
+
+        world.resolve_changes(ids[0], Detach::new::<u32>()).unwrap();
+        assert!(!world.get(ids[0]).unwrap().has::<u32>());
+        assert!(world.resolve_changes(ids[0], Detach::new::<u32>()).is_err());
+
+        world.resolve_changes(ids[1], Despawn::new()).unwrap();
+        assert!(world.get(ids[1]).is_none());
+        assert_eq!(world.len(), 2);
+
+        world.resolve_changes(ids[2], Spawn::new(|e| { e.insert(7u32); })).unwrap();
+        world.flush();
+        let spawned = world.entities()
+            .find(|e| e.get::<u32>() == Some(7))
+            .unwrap();
+        assert_eq!(world.len(), 3);
+        assert!(world.contains(spawned.id()));
+    }
+
+    #[test]
+    fn attaching_an_existing_component_replaces_it_in_place() {
+        let (mut world, ids) = world_with(&[0, 1]);
+        let loc = world.location(ids[0]).unwrap();
+        world.increment_change_tick();
+        world.attach(ids[0], 5u32, None).unwrap();
+
+        assert_eq!(world.location(ids[0]), Some(loc));
+        let entity = world.get(ids[0]).unwrap();
+        assert_eq!(entity.get::<u32>(), Some(5));
+        assert_eq!(entity.data().ticks(&ComponentKey::of::<u32>()), Some(ComponentTicks { added: 1, changed: 2 }));
+    }
+
+    #[test]
+    fn stale_handles_are_rejected() {
+        let (mut world, ids) = world_with(&[0, 1]);
+        world.despawn(ids[0]).unwrap();
+        assert!(world.despawn(ids[0]).is_err());
+
+        // the freed index is reused with a new generation
+        let new_id = world.new_entity_mut().id();
+        world.flush();
+        assert_eq!(new_id.index(), ids[0].index());
+        assert_ne!(new_id, ids[0]);
+        assert!(world.get(ids[0]).is_none());
+        assert!(world.get_mut(ids[0]).is_none());
+        assert!(world.resolve_changes(ids[0], Change::new(3u32)).is_err());
+        assert!(world.get(new_id).is_some());
+
+        world.get_mut(ids[1]).unwrap().insert(4u32).unwrap();
+        assert_eq!(world.get(ids[1]).unwrap().get::<u32>(), Some(4));
+    }
+
+    #[test]
+    fn components_are_dropped_once() {
+        let model = Arc::new(0u8);
+        let mut world = World::new();
+        let id = world.new_entity_mut().insert(model.clone()).insert(1u32).id();
+        let other = world.new_entity_mut().insert(model.clone()).id();
+        world.flush();
+        assert_eq!(Arc::strong_count(&model), 3);
+
+        // moving between archetypes neither copies nor drops
+        world.attach(id, 2.5f32, None).unwrap();
+        world.detach(id, &ComponentKey::of::<u32>()).unwrap();
+        assert_eq!(Arc::strong_count(&model), 3);
+        let snapshot = world.clone();
+        assert_eq!(Arc::strong_count(&model), 5);
+        world.insert(id, Arc::new(1u8), None).unwrap();
+        world.despawn(other).unwrap();
+        assert_eq!(Arc::strong_count(&model), 3);
+        drop(snapshot);
+        assert_eq!(Arc::strong_count(&model), 1);
+        // values of the wrong type are rejected
+        assert!(world.insert_value(id, &ComponentKey::of::<u32>(), ComponentValue::new(1u64)).is_err());
+        assert!(world.attach_value(id, ComponentKey::of::<u32>(), ComponentValue::new(1u64)).is_err());
+    }
+
+    #[test]
+    fn labelled_components_are_distinct() {
+        let mut world = World::new();
+        let id = world.new_entity_mut()
+            .insert(1u32)
+            .insert_labelled(2u32, "left")
+            .insert_labelled(3u32, "right")
+            .id();
+        world.flush();
+
+        let e = world.get(id).unwrap();
+        assert_eq!(e.get::<u32>(), Some(1));
+        assert_eq!(e.get_labelled::<u32>("left"), Some(2));
+        assert_eq!(e.get_labelled::<u32>("right"), Some(3));
+        // same size, different type
+        assert_eq!(e.get::<f32>(), None);
+        assert_eq!(e.get_labelled::<i32>("left"), None);
+
+        world.resolve_changes(id, Change::labelled(5u32, "left")).unwrap();
+        assert_eq!(world.get(id).unwrap().get_labelled::<u32>("left"), Some(5));
+        assert_eq!(world.get(id).unwrap().get::<u32>(), Some(1));
+    }
+}
@@ -1,21 +1,44 @@
-use std::time::{Duration, Instant};
+#[cfg(feature = "graphics")]
+use std::time::Instant;
 
+#[cfg(feature = "graphics")]
 use winit::{
     event::*,
     event_loop::{ControlFlow, EventLoop},
     window::WindowBuilder,
 };
 
+#[cfg(feature = "graphics")]
 use crate::asset::{AssetsToLoad, AssetStore};
 use crate::game::GameState;
+#[cfg(feature = "graphics")]
 use crate::render::{GPUState, Renderer};
+#[cfg(feature = "graphics")]
 use crate::render::sprite_render::SpriteRenderer;
 
 pub mod game;
 pub mod util;
+#[cfg(feature = "graphics")]
 pub mod render;
+#[cfg(feature = "graphics")]
 pub mod asset;
 
+/// Runs the game without a window or GPU, for servers, tests and CI.
+/// The ticks are run back to back, each advancing the simulation by the tick of the game's
+/// [`timestep`](GameState::timestep), so the outcome doesn't depend on how fast the machine is.
+/// Frame systems are not run, as nothing is rendered.
+pub fn run_headless(mut game_state: GameState, ticks: u64) -> GameState {
+    let _ = env_logger::try_init();
+    if let Err(err) = game_state.startup() {
+        log::error!("systems were not run: {}", err);
+    }
+    for _ in 0..ticks {
+        game_state.sim_tick(game_state.timestep.tick());
+    }
+    game_state
+}
+
+#[cfg(feature = "graphics")]
 pub async fn run(mut game_state: GameState, to_load: AssetsToLoad) {
     // Window setup
     env_logger::init();
@@ -31,9 +54,12 @@ pub async fn run(mut game_state: GameState, to_load: AssetsToLoad) {
     let mut sprite_renderer = SpriteRenderer::new(&gpu_state, asset_store.clone());
 
     // time keeping:
-    let sim_tick_duration: Duration = Duration::from_secs_f32(1.0 / 30.0);
     let mut prev_time = Instant::now();
 
+    if let Err(err) = game_state.startup() {
+        log::error!("systems were not run: {}", err);
+    }
+
     event_loop.run(move |event, window_target| {
         match event {
             Event::WindowEvent {
@@ -51,15 +77,11 @@ pub async fn run(mut game_state: GameState, to_load: AssetsToLoad) {
                 // You only need to call this if you've determined that you need to redraw in
                 // applications which do not always need to. Applications that redraw continuously
                 // can render here instead.
+                // the simulation runs in fixed ticks, catching up on slow frames,
+                // and the renderer blends the last two by the time left over
                 let now = Instant::now();
-                let delta = now - prev_time;
-                if delta >= sim_tick_duration {
-                    game_state.sim_tick(delta);
-                    // game_state.print_comps::<Transform2D>("pos");
-                    // game_state.print_comps::<Sprite>("sprite");
-                    sprite_renderer.pre_render(&gpu_state, &game_state);
-                    prev_time = now;
-                }
+                game_state.advance(now - prev_time);
+                prev_time = now;
                 gpu_state.window().request_redraw();
             },
             Event::WindowEvent {
@@ -71,9 +93,37 @@ pub async fn run(mut game_state: GameState, to_load: AssetsToLoad) {
                 // It's preferable for applications that do not render continuously to render in
                 // this event rather than in AboutToWait, since rendering in here allows
                 // the program to gracefully handle redraws requested by the OS.
+                game_state.frame();
+                sprite_renderer.pre_render(&gpu_state, &game_state);
                 gpu_state.render(&sprite_renderer);
             },
             _ => ()
         };
     }).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::game::entity::Change;
+    use crate::game::schedule::Times;
+    use crate::*;
+
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    struct Counter(u32);
+
+    #[test]
+    fn headless_runs_tick_the_simulation() {
+        let mut game = GameState::new();
+        let id = game.new_entity_mut().insert(Counter(0)).id();
+        game.add_linear_system(Times::SimulationTick, |entity, _| {
+            Some(Change::new(Counter(entity.get::<Counter>()?.0 + 1)))
+        });
+        game.add_linear_system(Times::Frame, |_, _| panic!("nothing is rendered"));
+
+        let game = run_headless(game, 30);
+        assert_eq!(game.get(id).unwrap().get::<Counter>(), Some(Counter(30)));
+        // a second of simulation went by, however long it took to run
+        assert_eq!(game.time().tick, 29);
+        assert!((game.time().elapsed.as_secs_f32() - 1.).abs() < 1e-3);
+    }
+}
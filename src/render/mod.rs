@@ -164,8 +164,7 @@ impl GPUState<'_> {
         let surface_caps = surface.get_capabilities(&adapter);
         let surface_format = surface_caps.formats.iter()
             .copied()
-            .filter(|f| f.is_srgb())
-            .next()
+            .find(|f| f.is_srgb())
             .unwrap_or(surface_caps.formats[0]);
 
         let config = surface.get_default_config(&adapter, size.width, size.height).unwrap();
@@ -193,7 +192,7 @@ impl GPUState<'_> {
         // if let Some(renderer) = renderers.first() {
         //     renderer.submit_pass(&self, &view);
         // }
-        renderer.render_pass(&self, &view);
+        renderer.render_pass(self, &view);
         frame.present();
     }
 
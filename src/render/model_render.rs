@@ -2,7 +2,7 @@ use wgpu::{RenderBundle, RenderPipeline, TextureView};
 
 use crate::asset::AssetStore;
 use crate::asset::model::Model;
-use crate::game::entity::{Component, Entity};
+use crate::game::entity::{Component, EntityBuilder};
 use crate::game::GameState;
 use crate::render::{GPUState, Renderer};
 use crate::util::res::Res;
@@ -10,9 +10,9 @@ use crate::util::res::Res;
 #[derive(Clone)]
 pub struct ModelComponent {
     pub model: Res<Model>,
-    instance_id: u32,
 }
 
+#[allow(dead_code)]
 pub struct ModelRenderer {
     asset_store: Res<AssetStore>,
     bundles: Vec<RenderBundle>,
@@ -36,17 +36,14 @@ impl Renderer for ModelRenderer {
 }
 
 impl Component for ModelComponent {
-    fn to_entity(mut self, entity: &mut Entity) {
-        //todo THIS IS also VERY BAD!!
-        self.instance_id = entity.id() as u32;
-        entity.mut_data().alloc(self, "model");
-        eprintln!("{}" , entity.data().get_content_string());
+    fn to_entity(self, entity: &mut EntityBuilder) {
+        // the instance is looked up by entity id in the AssetStore when rendering
+        entity.insert(self);
     }
 }
 
 impl ModelComponent {
     pub fn new(model: Res<Model>) -> Self {
-        //todo
-        ModelComponent { model, instance_id: 0 }
+        ModelComponent { model }
     }
 }
\ No newline at end of file
@@ -4,7 +4,7 @@ use std::fmt::{Display, Formatter};
 use wgpu::{RenderBundle, RenderBundleDescriptor, RenderPipeline, TextureView};
 
 use crate::asset::{AssetStore, MaterialId};
-use crate::game::entity::{Component, Entity};
+use crate::game::entity::{Component, EntityBuilder};
 use crate::game::GameState;
 use crate::game::transform::RawTransform2D;
 use crate::render::{GPUState, Renderer, SpriteVertex, Vertex};
@@ -13,7 +13,6 @@ use crate::util::res::Res;
 #[derive(Copy, Clone)]
 pub struct SpriteComponent {
     material_id: MaterialId,
-    instance_id: u32,
 }
 
 pub struct SpriteRenderer {
@@ -71,8 +70,11 @@ impl Renderer for SpriteRenderer {
         let assets = self.asset_store.read().unwrap();
         // creating bundles
         let mut bundles = Vec::new();
-        for entity in game.entities.iter() {
-            if let Some(sprite) = entity.data().get::<SpriteComponent>("sprite") {
+        for entity in game.entities() {
+            if let Some(sprite) = entity.get::<SpriteComponent>() {
+                let Some(instance) = assets.instance_2d(entity.id()) else {
+                    continue;
+                };
                 if let Some(material_res) = assets.get_material(sprite.material_id) {
                     let material = material_res.read().unwrap();
                     // create the encoder
@@ -94,8 +96,7 @@ impl Renderer for SpriteRenderer {
                     // pass the instance in
                     encoder.set_vertex_buffer(1, assets.instance_buffer_2d_slice(..));
                     // draw
-                    let i = sprite.instance_id..(sprite.instance_id + 1);
-                    encoder.draw(0..6, i);
+                    encoder.draw(0..6, instance..(instance + 1));
                     // output the bundle
                     let bundle = encoder.finish(&RenderBundleDescriptor {
                         label: Some("sprite bundle"),
@@ -137,23 +138,20 @@ impl Renderer for SpriteRenderer {
 }
 
 impl Component for SpriteComponent {
-    fn to_entity(mut self, entity: &mut Entity) {
-        //todo THIS IS VERY BAD!!
-        // make some kinda entity id to instance id mapping in AssetStore
-        self.instance_id = entity.id() as u32;
-        entity.mut_data().alloc(self, "sprite");
-        eprintln!("{}" , entity.data().get_content_string());
+    fn to_entity(self, entity: &mut EntityBuilder) {
+        // the instance is looked up by entity id in the AssetStore when rendering
+        entity.insert(self);
     }
 }
 
 impl SpriteComponent {
     pub fn new(material_id: MaterialId) -> Self {
-        SpriteComponent { material_id, instance_id: 0 }
+        SpriteComponent { material_id }
     }
 }
 
 impl Display for SpriteComponent {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Sprite[mat={},inst={}", self.material_id, self.instance_id)
+        write!(f, "Sprite[mat={}]", self.material_id)
     }
 }
\ No newline at end of file
@@ -1,109 +1,316 @@
-use std::{mem, ptr};
+use std::borrow::Borrow;
 use std::collections::HashMap;
-use std::mem::transmute;
+use std::fmt::Display;
+use std::hash::Hash;
+use std::mem::{ManuallyDrop, MaybeUninit};
 
 use anyhow::anyhow;
-use mem_macros::size_of;
-
-// todo make thread safe
-// todo possible memory leak: when arena is deallocated,
-//  any resource referenced a data block will not be deallocated
-pub struct ComponentArena {
-    data: Vec<u8>,
-    labels: HashMap<String, (usize, usize)>, // start to end
+
+use crate::game::component::{assume_init, ComponentType, ComponentValue};
+
+/// Stores differently sized blocks of bytes next to each other, each one found by its label.
+/// Labels are strings by default, but any hashable key can be used.
+///
+/// Every block remembers the type it was stored as. Typed reads and writes must use that type,
+/// and the values are cloned and dropped along with the arena, so it can hold any `Clone` type.
+/// Values must be `Send + Sync`, so the arena is too.
+/// Blocks are not aligned, and are read with unaligned loads.
+///
+/// Removed blocks leave holes that are reused by later allocations,
+/// and [`ComponentArena::compact`] closes them all.
+pub struct ComponentArena<K = String> {
+    data: Vec<MaybeUninit<u8>>,
+    labels: HashMap<K, Block>,
+    free: Vec<(usize, usize)>, // holes, sorted and never touching each other or the end
 }
 
-impl ComponentArena {
-    pub fn new() -> Self {
+#[derive(Copy, Clone)]
+struct Block {
+    start: usize,
+    end: usize,
+    ty: ComponentType,
+}
+
+/// How much memory an arena uses, in bytes.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ArenaStats {
+    /// Bytes taken by blocks
+    pub used: usize,
+    /// Bytes in holes left by removed blocks
+    pub free: usize,
+    /// Bytes allocated for the data
+    pub capacity: usize,
+    pub blocks: usize,
+    pub holes: usize,
+}
+
+impl<K: Hash + Eq> Default for ComponentArena<K> {
+    fn default() -> Self {
         ComponentArena {
             data: Vec::new(),
             labels: HashMap::new(),
+            free: Vec::new(),
         }
     }
+}
 
-    pub fn get<T: Clone>(&self, label: &str) -> Option<T> {
-        let (start, end) = self.labels.get(label)?;
-        let slice = self.data.as_slice().get(*start..*end)?.as_ptr();
-        Some(unsafe {
-            ptr::read(transmute::<*const u8, *const T>(slice))
-        }.clone())
+impl<K: Clone + Hash + Eq> Clone for ComponentArena<K> {
+    fn clone(&self) -> Self {
+        // holes and raw bytes are copied as they are, values are cloned over their copy
+        let mut data = self.data.clone();
+        for block in self.labels.values() {
+            unsafe {
+                block.ty.clone_into(&self.data[block.start..block.end], &mut data[block.start..block.end]);
+            }
+        }
+        ComponentArena {
+            data,
+            labels: self.labels.clone(),
+            free: self.free.clone(),
+        }
     }
+}
 
-    pub fn get_bytes(&self, label: &str) -> Option<&[u8]> {
-        let (start, end) = self.labels.get(label)?;
-        self.data.as_slice().get(*start..*end)
+impl<K> Drop for ComponentArena<K> {
+    fn drop(&mut self) {
+        for block in self.labels.values() {
+            unsafe { block.ty.drop_in_place(&mut self.data[block.start..block.end]) };
+        }
     }
+}
 
-    pub fn get_length(&self, label: &str) -> Option<usize> {
-        let (start, end) = self.labels.get(label)?;
-        return Some(end - start);
+impl ComponentArena {
+    pub fn new() -> Self {
+        Self::default()
     }
+}
 
-    //todo test this
-    #[allow(dead_code)]
-    pub fn get_mut_bytes(&mut self, label: &str) -> Option<&mut [u8]> {
-        let (start, end) = self.labels.get(label)?;
-        self.data.as_mut_slice().get_mut(*start..*end)
+impl<K: Hash + Eq> ComponentArena<K> {
+
+    /// Clones the value stored under the label, if it was stored as a `T`.
+    pub fn get<T: Clone + 'static, Q>(&self, label: &Q) -> Option<T>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        let block = self.labels.get(label)?;
+        if !block.ty.is::<T>() {
+            return None;
+        }
+        let value = unsafe {
+            ManuallyDrop::new((self.data[block.start..].as_ptr() as *const T).read_unaligned())
+        };
+        Some((*value).clone())
     }
 
-    pub fn alloc_raw(&mut self, data: &[u8], label: &str) {
+    /// The bytes of a block of plain bytes.
+    /// Blocks holding a Rust value are only read through [`ComponentArena::get`].
+    pub fn get_bytes<Q>(&self, label: &Q) -> Option<&[u8]>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        let block = self.labels.get(label)?;
+        // raw blocks are written from initialized bytes only
+        block.ty.is_raw().then(|| unsafe { assume_init(&self.data[block.start..block.end]) })
+    }
+
+    pub fn get_length<Q>(&self, label: &Q) -> Option<usize>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        let block = self.labels.get(label)?;
+        Some(block.end - block.start)
+    }
+
+    /// The type the block under the label was stored as.
+    pub fn get_type<Q>(&self, label: &Q) -> Option<&ComponentType>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        Some(&self.labels.get(label)?.ty)
+    }
+
+    /// Gives mutable access to a block of plain bytes.
+    /// Blocks holding a Rust value are only changed through [`ComponentArena::insert`].
+    pub fn get_mut_bytes<Q>(&mut self, label: &Q) -> Option<&mut [u8]>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        let block = self.labels.get(label)?;
+        if block.ty.is_raw() {
+            let bytes = &mut self.data[block.start..block.end];
+            Some(unsafe { &mut *(bytes as *mut [MaybeUninit<u8>] as *mut [u8]) })
+        } else {
+            None
+        }
+    }
+
+    /// Stores plain bytes under the label. A block already stored under it is replaced,
+    /// in place if it has the same size.
+    pub fn alloc_raw(&mut self, data: &[u8], label: impl Into<K>) {
+        self.alloc_value(ComponentValue::raw(data), label)
+    }
+
+    pub fn alloc<T: Clone + Send + Sync + 'static>(&mut self, data: T, label: impl Into<K>) {
+        self.alloc_value(ComponentValue::new(data), label)
+    }
+
+    /// Moves a value in under the label. A block already stored under it is dropped and replaced,
+    /// in place if it has the same size.
+    pub fn alloc_value(&mut self, value: ComponentValue, label: impl Into<K>) {
+        let label = label.into();
+        let ty = *value.ty();
+        let bytes = value.into_raw();
+        if let Some(block) = self.labels.get(&label).copied() {
+            unsafe { block.ty.drop_in_place(&mut self.data[block.start..block.end]) };
+            if block.end - block.start == bytes.len() {
+                self.data[block.start..block.end].copy_from_slice(&bytes);
+                self.labels.insert(label, Block { ty, ..block });
+                return;
+            }
+            self.release(block.start, block.end);
+        }
+        let start = self.reserve(bytes.len());
+        let end = start + bytes.len();
+        self.data[start..end].copy_from_slice(&bytes);
+        self.labels.insert(label, Block { start, end, ty });
+    }
+
+    /// Finds room for a block: the first hole big enough, or the end of the data.
+    fn reserve(&mut self, len: usize) -> usize {
+        if len == 0 {
+            return 0;
+        }
+        if let Some(i) = self.free.iter().position(|(start, end)| end - start >= len) {
+            let (start, end) = self.free[i];
+            if end - start == len {
+                self.free.remove(i);
+            } else {
+                self.free[i].0 += len;
+            }
+            return start;
+        }
         let start = self.data.len();
-        let end = start + data.len();
-        self.labels.insert(label.to_string(), (start, end));
-        self.data.extend_from_slice(data);
+        self.data.resize(start + len, MaybeUninit::uninit());
+        start
     }
 
-    pub fn alloc<T: Clone>(&mut self, data: T, label: &str) {
-        let p: *const T = &data;
-        let bytes: &[u8] = unsafe {
-            let t_slice = std::slice::from_raw_parts(p, size_of!(T));
-            transmute::<&[T], &[u8]>(t_slice)
-        };
-        self.alloc_raw(&bytes, label)
+    /// Turns a block into a hole, merging it with the holes next to it.
+    fn release(&mut self, mut start: usize, mut end: usize) {
+        if start == end {
+            return;
+        }
+        let i = self.free.partition_point(|(s, _)| *s < start);
+        // merge with the hole after, then the one before
+        if i < self.free.len() && self.free[i].0 == end {
+            end = self.free.remove(i).1;
+        }
+        if i > 0 && self.free[i - 1].1 == start {
+            start = self.free.remove(i - 1).0;
+        }
+        if end == self.data.len() {
+            self.data.truncate(start);
+        } else {
+            let i = self.free.partition_point(|(s, _)| *s < start);
+            self.free.insert(i, (start, end));
+        }
     }
 
-    pub fn insert_raw(&mut self, data: &[u8], label: &str) -> anyhow::Result<()> {
-        //todo add proper errors to this function, rather than anyhow
-        let (start, end) = match self.labels.get(label) {
-            None => return Err(anyhow!("label does not exist!")),
-            Some(r) => *r,
-        };
-        if end - start > data.len() {
-            return Err(anyhow!("data is longer than the destination!"));
+    /// Removes a block and hands back its value. Its room is reused by later allocations.
+    pub fn take<Q>(&mut self, label: &Q) -> Option<ComponentValue>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        let block = self.labels.remove(label)?;
+        let bytes = self.data[block.start..block.end].to_vec();
+        self.release(block.start, block.end);
+        Some(unsafe { ComponentValue::from_raw(block.ty, bytes) })
+    }
+
+    /// Removes a block, dropping its value. Returns whether there was one.
+    pub fn remove<Q>(&mut self, label: &Q) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.take(label).is_some()
+    }
+
+    /// Moves every block to the front, closing the holes, and gives the unused memory back.
+    pub fn compact(&mut self) {
+        let mut blocks: Vec<&mut Block> = self.labels.values_mut().collect();
+        blocks.sort_by_key(|block| block.start);
+        let mut next = 0;
+        for block in blocks {
+            let len = block.end - block.start;
+            if len == 0 {
+                continue;
+            }
+            self.data.copy_within(block.start..block.end, next);
+            block.start = next;
+            block.end = next + len;
+            next += len;
         }
-        //copying data into the vector:
-        for i in start..end {
-            let _ = mem::replace(self.data.get_mut(i).unwrap(), data[i - start]);
+        self.data.truncate(next);
+        self.data.shrink_to_fit();
+        self.free.clear();
+    }
+
+    pub fn stats(&self) -> ArenaStats {
+        let free = self.free.iter().map(|(start, end)| end - start).sum();
+        ArenaStats {
+            used: self.data.len() - free,
+            free,
+            capacity: self.data.capacity(),
+            blocks: self.labels.len(),
+            holes: self.free.len(),
         }
+    }
+
+    /// Overwrites a block of plain bytes. Fails if the label does not exist,
+    /// the block holds a Rust value, or the data is shorter than the block.
+    pub fn insert_raw<Q>(&mut self, data: &[u8], label: &Q) -> anyhow::Result<()>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        let dest = self.get_mut_bytes(label)
+            .ok_or_else(|| anyhow!("label does not exist, or does not hold raw bytes!"))?;
+        if dest.len() > data.len() {
+            return Err(anyhow!("data is shorter than the destination!"));
+        }
+        let len = dest.len();
+        dest.copy_from_slice(&data[..len]);
         Ok(())
     }
 
-    pub fn insert<T: Clone>(&mut self, data: T, label: &str) -> anyhow::Result<()> {
-        let p: *const T = &data;
-        let bytes: &[u8] = unsafe {
-            let t_slice = std::slice::from_raw_parts(p, size_of!(T));
-            transmute::<&[T], &[u8]>(t_slice)
-        };
-        self.insert_raw(bytes, label)
+    /// Replaces the value stored under the label, dropping the old one.
+    /// Fails if the label does not exist or was stored as another type.
+    pub fn insert<T: Clone + Send + Sync + 'static, Q>(&mut self, data: T, label: &Q) -> anyhow::Result<()>
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        let block = self.labels.get(label)
+            .ok_or_else(|| anyhow!("label does not exist!"))?;
+        if !block.ty.is::<T>() {
+            return Err(anyhow!("label holds a {:?}, not a {}!", block.ty, std::any::type_name::<T>()));
+        }
+        let dest = &mut self.data[block.start..block.end];
+        unsafe {
+            block.ty.drop_in_place(dest);
+            (dest.as_mut_ptr() as *mut T).write_unaligned(data);
+        }
+        Ok(())
     }
 
-    pub fn has(&self, label: &str) -> bool {
-        self.labels.get(label).is_some()
+    pub fn has<Q>(&self, label: &Q) -> bool
+        where K: Borrow<Q>, Q: Hash + Eq + ?Sized {
+        self.labels.contains_key(label)
     }
 
-    pub fn labels(&self) -> Vec<String> {
+    pub fn labels(&self) -> Vec<K> where K: Clone {
         let mut out = Vec::new();
         for key in self.labels.keys() {
             out.push(key.clone());
         }
-        return out;
+        out
+    }
+
+    /// Empties the arena, handing back every value along with its label.
+    pub fn into_values(mut self) -> Vec<(K, ComponentValue)> {
+        let blocks: Vec<(K, Block)> = self.labels.drain().collect();
+        blocks.into_iter()
+            .map(|(label, block)| {
+                let bytes = self.data[block.start..block.end].to_vec();
+                (label, unsafe { ComponentValue::from_raw(block.ty, bytes) })
+            })
+            .collect()
     }
 
     #[allow(dead_code)]
-    pub fn get_content_string(&self) -> String {
+    pub fn get_content_string(&self) -> String where K: Display {
         let mut s = String::new();
-        for (label, range) in self.labels.iter() {
-            s += &format!("[{} <-- {} --> {}]", range.0, label, range.1);
+        for (label, block) in self.labels.iter() {
+            s += &format!("[{} <-- {}: {:?} --> {}]", block.start, label, block.ty, block.end);
         }
         s
     }
@@ -111,6 +318,8 @@ impl ComponentArena {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
     use bytemuck::{Pod, Zeroable};
 
     use crate::util::arena::*;
@@ -156,9 +365,9 @@ mod tests {
             let s2 = TestStruct2 { f: 0.2345 };
             let s3 = TestStruct3 { x: 10, arr: [1.1, 2.2] };
 
-            arena.alloc(s1, "s1");
-            arena.alloc(s2, "s2");
-            arena.alloc(s3, "s3");
+            arena.alloc_raw(bytemuck::bytes_of(&s1), "s1");
+            arena.alloc_raw(bytemuck::bytes_of(&s2), "s2");
+            arena.alloc_raw(bytemuck::bytes_of(&s3), "s3");
         }
         // access
         {
@@ -235,6 +444,102 @@ mod tests {
         }
     }
 
+    #[test]
+    fn removed_blocks_are_reused() {
+        let mut arena = ComponentArena::new();
+        arena.alloc_raw(&[1; 4], "a");
+        arena.alloc_raw(&[2; 8], "b");
+        arena.alloc_raw(&[3; 4], "c");
+        arena.alloc_raw(&[], "empty");
+
+        assert_eq!(arena.take("b").map(|value| value.raw_bytes().unwrap().to_vec()), Some(vec![2; 8]));
+        assert!(!arena.has("b"));
+        assert!(!arena.remove("b"));
+        assert_eq!(arena.stats(), ArenaStats { used: 8, free: 8, blocks: 3, holes: 1, ..arena.stats() });
+
+        // fits in the hole, which gets smaller
+        arena.alloc_raw(&[4; 2], "d");
+        assert_eq!(arena.stats().free, 6);
+        // a bigger block moves out of its place, leaving a hole that merges with the one next to it
+        arena.alloc_raw(&[5; 12], "a");
+        assert_eq!((arena.stats().free, arena.stats().holes), (4 + 6, 2));
+        // removing the last block gives its memory back instead of leaving a hole
+        arena.remove("a");
+        assert_eq!((arena.stats().used, arena.stats().free), (6, 10));
+
+        for (label, bytes) in [("c", &[3u8; 4][..]), ("d", &[4; 2]), ("empty", &[])] {
+            assert_eq!(arena.get_bytes(label), Some(bytes));
+        }
+        arena.compact();
+        assert_eq!(arena.stats(), ArenaStats { used: 6, free: 0, capacity: 6, blocks: 3, holes: 0 });
+        for (label, bytes) in [("c", &[3u8; 4][..]), ("d", &[4; 2]), ("empty", &[])] {
+            assert_eq!(arena.get_bytes(label), Some(bytes));
+        }
+    }
+
+    #[test]
+    fn churn_does_not_grow_memory() {
+        let mut arena = ComponentArena::new();
+        for i in 0..1000 {
+            arena.alloc(TestStruct1 { i, u: 0 }, format!("s{}", i % 10));
+            arena.alloc(TestStruct3 { x: i, arr: [0.; 2] }, "s3");
+            if i % 3 == 0 {
+                arena.remove(&format!("s{}", i % 7));
+            }
+        }
+        // ten blocks of 8 bytes and one of 12 at most, and the holes are being filled again
+        let stats = arena.stats();
+        assert!(stats.used <= 8 * 10 + 12);
+        assert!(stats.free < stats.used);
+    }
+
+    #[test]
+    fn values_keep_their_type() {
+        let mut arena = ComponentArena::new();
+        // the u64 ends up at an odd offset
+        arena.alloc(1u8, "byte");
+        arena.alloc(u64::MAX - 1, "long");
+        arena.alloc_raw(&[0; 4], "raw");
+
+        assert_eq!(arena.get::<u64, _>("long"), Some(u64::MAX - 1));
+        assert_eq!(arena.get::<i64, _>("long"), None);
+        assert_eq!(arena.get::<u32, _>("raw"), None);
+        assert!(arena.insert(1i64, "long").is_err());
+        assert!(arena.insert_raw(&[0; 8], "long").is_err());
+        arena.insert(3u64, "long").unwrap();
+        arena.insert_raw(&[1; 4], "raw").unwrap();
+        assert_eq!(arena.get::<u64, _>("long"), Some(3));
+        assert_eq!(arena.get_bytes("raw"), Some(&[1u8; 4][..]));
+        // values are not plain bytes
+        assert_eq!(arena.get_bytes("long"), None);
+        assert!(arena.get_type("long").unwrap().is::<u64>());
+    }
+
+    #[test]
+    fn values_are_cloned_and_dropped() {
+        let model = Arc::new("model".to_string());
+        let mut arena = ComponentArena::new();
+        arena.alloc(model.clone(), "a");
+        arena.alloc(model.clone(), "b");
+        let copy = arena.clone();
+        assert_eq!(Arc::strong_count(&model), 5);
+
+        // replaced and removed values are dropped
+        arena.alloc(Arc::new("other".to_string()), "a");
+        arena.insert(Arc::new("other".to_string()), "b").unwrap();
+        assert_eq!(Arc::strong_count(&model), 3);
+        drop(copy);
+        assert_eq!(Arc::strong_count(&model), 1);
+
+        arena.alloc(model.clone(), "c");
+        let taken = arena.take("c").unwrap().downcast::<Arc<String>>().unwrap();
+        assert!(Arc::ptr_eq(&taken, &model));
+        assert_eq!(Arc::strong_count(&model), 2);
+        drop(arena);
+        drop(taken);
+        assert_eq!(Arc::strong_count(&model), 1);
+    }
+
     #[test]
     fn double_get() {
         let mut arena = ComponentArena::new();
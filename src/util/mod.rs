@@ -1,16 +1,27 @@
-use std::intrinsics::transmute;
-use std::ptr;
-
-use mem_macros::size_of;
+use bytemuck::Pod;
 
 pub mod arena;
 pub mod res;
 
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Either<T1, T2> {
     This(T1),
     That(T2),
 }
 
+/// Iterates over whichever of the two iterators it holds.
+impl<I1: Iterator, I2: Iterator<Item = I1::Item>> Iterator for Either<I1, I2> {
+    type Item = I1::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Either::This(iter) => iter.next(),
+            Either::That(iter) => iter.next(),
+        }
+    }
+}
+
+/// Plain data that can be turned into bytes and back.
 pub trait Byteable {
     fn from_bytes(bytes: &[u8]) -> Self;
 
@@ -19,13 +30,10 @@ pub trait Byteable {
     fn into_bytes(self) -> Vec<u8>;
 }
 
-impl<T> Byteable for T {
+impl<T: Pod> Byteable for T {
+    /// Panics if there are not exactly as many bytes as in a `T`. The bytes don't need to be aligned.
     fn from_bytes(bytes: &[u8]) -> Self {
-        assert_eq!(bytes.len(), size_of!(T));
-        let p: *const u8 = bytes.as_ptr();
-        unsafe {
-            ptr::read(transmute::<*const u8, *const T>(p))
-        }
+        bytemuck::pod_read_unaligned(bytes)
     }
 
     #[inline]
@@ -34,11 +42,6 @@ impl<T> Byteable for T {
     }
 
     fn into_bytes(self) -> Vec<u8> {
-        let p: *const T = &self;
-        let slice = unsafe {
-            let t_slice = std::slice::from_raw_parts(p, size_of!(T));
-            transmute::<&[T], &[u8]>(t_slice)
-        };
-        Vec::from(slice)
+        bytemuck::bytes_of(&self).to_vec()
     }
 }